@@ -1,9 +1,21 @@
-use std::fs::File;
-use std::io::prelude::*;
+use env::{env_set, Env};
+use data::{AtomVal, AtomType, AtomRet, AtomError, AtomMap, c_int, c_nil, c_list, c_vec, c_map,
+           c_list_of, c_vec_of, c_set, c_lazy_seq, c_lazy_cons, c_record, c_partial, c_reduced,
+           c_string, c_symbol, c_keyword, c_func, c_gensym, c_ref, seq, seq_iter, seq_items, uncons,
+           realize_printed};
+use list::AtomList;
+use map::AtomSet;
+use multi;
+use eval::eval_str_all;
+use load::load_file;
+use dynamic;
+use gc;
+use namespace::{c_registry, find_or_create, switch, CORE_NS, USER_NS};
 
-use env::{c_env, env_set, Env};
-use data::{AtomVal, AtomType, AtomRet, c_int, c_nil, c_list, c_symbol, c_func};
-use eval::eval_str;
+const PRELUDE: &'static str = include_str!("core.clrs");
+
+// Dynamic var printing functions write to, defined by the prelude.
+const OUT: &'static str = "rulsp.core/*out*";
 
 fn safe_get(args: &[AtomVal], index: usize) -> AtomVal {
     args.get(index).cloned().unwrap_or_else(c_nil)
@@ -31,7 +43,11 @@ fn int_op<F>(f: F, args: &[AtomVal]) -> AtomRet
 
 }
 
+// Without args, the identity of the operation, as the init of a transduce.
 fn add(args: &[AtomVal]) -> AtomRet {
+    if args.is_empty() {
+        return Ok(c_int(0));
+    }
     int_op(|acc, v| Ok(c_int(acc + v)), args)
 }
 
@@ -40,6 +56,9 @@ fn sub(args: &[AtomVal]) -> AtomRet {
 }
 
 fn mul(args: &[AtomVal]) -> AtomRet {
+    if args.is_empty() {
+        return Ok(c_int(1));
+    }
     int_op(|acc, v| Ok(c_int(acc * v)), args)
 }
 
@@ -47,10 +66,216 @@ fn div(args: &[AtomVal]) -> AtomRet {
     int_op(|acc, v| Ok(c_int(acc / v)), args)
 }
 
+// Persistent list of `coll`, shared with it when it already is a list.
+fn as_list(coll: &AtomVal) -> Result<AtomList, AtomError> {
+    match **coll {
+        AtomType::List(ref list) => Ok(list.clone()),
+        AtomType::Nil => Ok(AtomList::new()),
+        _ => Ok(AtomList::from_vec(seq_items(coll)?)),
+    }
+}
+
+// [value coll] - the list shares coll's elements when it is a list, a lazy
+// sequence is left unrealized
 fn cons(args: &[AtomVal]) -> AtomRet {
-    let mut list = vec![safe_get(args, 0)];
-    list.extend(safe_get(args, 1).get_list()?.iter().cloned());
-    Ok(c_list(&list))
+    let coll = safe_get(args, 1);
+    if let AtomType::LazySeq(_) = *coll {
+        return Ok(c_lazy_cons(safe_get(args, 0), coll));
+    }
+
+    Ok(c_list_of(as_list(&coll)?.cons(safe_get(args, 0))))
+}
+
+// [& colls] - joined into a list sharing the last one, or into a lazy
+// sequence when the last one is lazy
+fn concat(args: &[AtomVal]) -> AtomRet {
+    let mut colls = args.iter().rev();
+    if let Some(&AtomType::LazySeq(_)) = args.last().map(|last| &**last) {
+        let mut joined = colls.next().cloned().unwrap_or_else(c_nil);
+        for coll in colls {
+            for item in seq_items(coll)?.into_iter().rev() {
+                joined = c_lazy_cons(item, joined);
+            }
+        }
+        return Ok(joined);
+    }
+
+    let mut list = match colls.next() {
+        Some(last) => as_list(last)?,
+        None => AtomList::new(),
+    };
+
+    for coll in colls {
+        for item in seq_items(coll)?.into_iter().rev() {
+            list = list.cons(item);
+        }
+    }
+
+    Ok(c_list_of(list))
+}
+
+fn vec(args: &[AtomVal]) -> AtomRet {
+    match *safe_get(args, 0) {
+        AtomType::Vec(_) => Ok(safe_get(args, 0)),
+        _ => Ok(c_vec(&seq_items(&safe_get(args, 0))?)),
+    }
+}
+
+// [k1 v1 k2 v2 ...]
+fn hash_map(args: &[AtomVal]) -> AtomRet {
+    match AtomMap::from_flat(args) {
+        Some(map) => Ok(c_map(map)),
+        None => Err(AtomError::InvalidArgument("hash-map expects even number of arguments".to_string())),
+    }
+}
+
+// [& items]
+fn hash_set(args: &[AtomVal]) -> AtomRet {
+    Ok(c_set(AtomSet::from_items(args)))
+}
+
+// [coll]
+fn set(args: &[AtomVal]) -> AtomRet {
+    match *safe_get(args, 0) {
+        AtomType::Set(_) => Ok(safe_get(args, 0)),
+        _ => Ok(c_set(AtomSet::from_items(&seq_items(&safe_get(args, 0))?))),
+    }
+}
+
+// [coll & items] - adds items where it's cheap: to the front of a list, the
+// end of a vector, a set, or [key value] entries to a map. An empty vector
+// without args.
+fn conj(args: &[AtomVal]) -> AtomRet {
+    if args.is_empty() {
+        return Ok(c_vec(&[]));
+    }
+
+    let mut coll = safe_get(args, 0);
+    for item in args.iter().skip(1) {
+        let updated = match *coll {
+            AtomType::Nil => c_list(&[item.clone()]),
+            AtomType::List(ref list) => c_list_of(list.cons(item.clone())),
+            AtomType::LazySeq(_) => c_lazy_cons(item.clone(), coll.clone()),
+            AtomType::Vec(ref vec) => c_vec_of(vec.conj(item.clone())),
+            AtomType::Set(ref set) => c_set(set.conj(item.clone())),
+            AtomType::Map(_) |
+            AtomType::Record(_) => {
+                let entry = item.get_list()?;
+                if entry.len() != 2 {
+                    return Err(AtomError::InvalidArgument(format!("{} is not a [key value] entry", item)));
+                }
+                assoc(&[coll.clone(), entry[0].clone(), entry[1].clone()])?
+            }
+            ref other => return Err(AtomError::InvalidType("collection".to_string(), other.format(true))),
+        };
+        coll = updated;
+    }
+
+    Ok(coll)
+}
+
+// [coll key value & kvs] - map with the keys set, or vector with the values
+// at the indexes replaced
+fn assoc(args: &[AtomVal]) -> AtomRet {
+    if args.len() % 2 == 0 {
+        return Err(AtomError::InvalidArgument("assoc expects a value for each key".to_string()));
+    }
+
+    let mut coll = safe_get(args, 0);
+    for pair in args[1..].chunks(2) {
+        let (key, value) = (pair[0].clone(), pair[1].clone());
+        let updated = match *coll {
+            AtomType::Nil => c_map(AtomMap::new().assoc(key, value)),
+            AtomType::Map(ref map) => c_map(map.assoc(key, value)),
+            AtomType::Record(ref record) => c_record(record.assoc(key, value)),
+            AtomType::Vec(ref vec) => {
+                let index = key.get_int()?;
+                match if index < 0 { None } else { vec.assoc(index as usize, value) } {
+                    Some(vec) => c_vec_of(vec),
+                    None => return Err(AtomError::InvalidArgument(format!("index {} out of bounds", index))),
+                }
+            }
+            ref other => return Err(AtomError::InvalidType("Map".to_string(), other.format(true))),
+        };
+        coll = updated;
+    }
+
+    Ok(coll)
+}
+
+// [map & keys]
+fn dissoc(args: &[AtomVal]) -> AtomRet {
+    match *safe_get(args, 0) {
+        AtomType::Nil => Ok(c_nil()),
+        AtomType::Map(ref map) => {
+            let mut map = map.clone();
+            for key in args.iter().skip(1) {
+                map.remove(key);
+            }
+            Ok(c_map(map))
+        }
+        AtomType::Record(ref record) => {
+            let mut record = record.clone();
+            for (index, key) in args.iter().enumerate().skip(1) {
+                record = match record.dissoc(key) {
+                    Ok(record) => record,
+                    // without one of its fields it's no longer a record
+                    Err(map) => {
+                        let mut rest = vec![c_map(map)];
+                        rest.extend_from_slice(&args[index + 1..]);
+                        return dissoc(&rest);
+                    }
+                };
+            }
+            Ok(c_record(record))
+        }
+        ref other => Err(AtomError::InvalidType("Map".to_string(), other.format(true))),
+    }
+}
+
+// [set & items]
+fn disj(args: &[AtomVal]) -> AtomRet {
+    match *safe_get(args, 0) {
+        AtomType::Nil => Ok(c_nil()),
+        AtomType::Set(ref set) => {
+            let mut set = set.clone();
+            for item in args.iter().skip(1) {
+                set.remove(item);
+            }
+            Ok(c_set(set))
+        }
+        ref other => Err(AtomError::InvalidType("Set".to_string(), other.format(true))),
+    }
+}
+
+// Value stored under `key` in a map, at index `key` of a vector, or `key`
+// itself when a set contains it.
+fn lookup(coll: &AtomVal, key: &AtomVal) -> Option<AtomVal> {
+    match **coll {
+        AtomType::Map(ref map) => map.get(key).cloned(),
+        AtomType::Record(ref record) => record.get(key).cloned(),
+        AtomType::Set(ref set) if set.contains(key) => Some(key.clone()),
+        AtomType::Vec(ref vec) => {
+            match **key {
+                AtomType::Int(index) if index >= 0 => vec.get(index as usize).cloned(),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// [coll key default?]
+fn get(args: &[AtomVal]) -> AtomRet {
+    Ok(lookup(&safe_get(args, 0), &safe_get(args, 1)).unwrap_or_else(|| safe_get(args, 2)))
+}
+
+// [coll key]
+fn contains(args: &[AtomVal]) -> AtomRet {
+    match lookup(&safe_get(args, 0), &safe_get(args, 1)) {
+        Some(_) => Ok(c_int(1)),
+        None => Ok(c_nil()),
+    }
 }
 
 fn list(args: &[AtomVal]) -> AtomRet {
@@ -71,9 +296,40 @@ fn is_nil(args: &[AtomVal]) -> AtomRet {
     }
 }
 
+// [coll] - collections that know their size aren't walked
+fn is_empty(args: &[AtomVal]) -> AtomRet {
+    let coll = safe_get(args, 0);
+    let empty = match *coll {
+        AtomType::Nil => true,
+        AtomType::List(ref list) => list.is_empty(),
+        AtomType::Vec(ref vec) => vec.is_empty(),
+        AtomType::Map(ref map) => map.len() == 0,
+        AtomType::Record(ref record) => record.len() == 0,
+        AtomType::Set(ref set) => set.len() == 0,
+        AtomType::Str(ref str) => str.is_empty(),
+        _ => uncons(&coll)?.is_none(),
+    };
+
+    Ok(if empty { c_int(1) } else { c_nil() })
+}
 
 fn count(args: &[AtomVal]) -> AtomRet {
-    Ok(c_int(safe_get(args, 0).get_list()?.len() as i64))
+    match *safe_get(args, 0) {
+        AtomType::List(ref list) => Ok(c_int(list.len() as i64)),
+        AtomType::Vec(ref vec) => Ok(c_int(vec.len() as i64)),
+        AtomType::Map(ref map) => Ok(c_int(map.len() as i64)),
+        AtomType::Record(ref record) => Ok(c_int(record.len() as i64)),
+        AtomType::Set(ref set) => Ok(c_int(set.len() as i64)),
+        AtomType::Str(ref str) => Ok(c_int(str.chars().count() as i64)),
+        _ => {
+            let mut count = 0;
+            for item in seq_iter(&safe_get(args, 0)) {
+                item?;
+                count += 1;
+            }
+            Ok(c_int(count))
+        }
+    }
 }
 
 
@@ -81,58 +337,700 @@ fn nth(args: &[AtomVal]) -> AtomRet {
     trace!("action=nth args={:?}", args);
     let n = safe_get(args, 1).get_int().unwrap_or(0);
 
-    Ok(safe_get(&safe_get(args, 0).get_list()?, n as usize))
+    match *safe_get(args, 0) {
+        AtomType::List(ref list) => Ok(list.get(n as usize).cloned().unwrap_or_else(c_nil)),
+        AtomType::Vec(ref vec) => Ok(vec.get(n as usize).cloned().unwrap_or_else(c_nil)),
+        _ => {
+            match uncons(&drop_items(n, safe_get(args, 0))?)? {
+                Some((item, _)) => Ok(item),
+                None => Ok(c_nil()),
+            }
+        }
+    }
 }
 
 
+// [coll]
+// [coll] - taken straight from vectors, maps, sets and strings instead of
+// copying them into a seq
+fn first(args: &[AtomVal]) -> AtomRet {
+    let coll = safe_get(args, 0);
+    let first = match *coll {
+        AtomType::Vec(ref vec) => vec.get(0).cloned(),
+        AtomType::Set(ref set) => set.iter().next().cloned(),
+        AtomType::Map(ref map) => {
+            map.iter().next().map(|&(ref key, ref value)| c_vec(&[key.clone(), value.clone()]))
+        }
+        AtomType::Str(ref str) => str.chars().next().map(|c| c_string(&c.to_string())),
+        _ => uncons(&coll)?.map(|(first, _)| first),
+    };
+
+    Ok(first.unwrap_or_else(c_nil))
+}
+
 fn rest(args: &[AtomVal]) -> AtomRet {
-    match safe_get(args, 0).get_list() {
-        Ok(seq) => {
-            if seq.len() > 0 {
-                Ok(c_list(&seq[1..seq.len()]))
+    match *safe_get(args, 0) {
+        AtomType::Nil => Ok(c_nil()),
+        AtomType::List(ref list) => Ok(c_list_of(list.rest())),
+        _ => Ok(uncons(&safe_get(args, 0))?.map(|(_, rest)| rest).unwrap_or_else(|| c_list(&[]))),
+    }
+}
+
+// [coll] - nil when coll is empty
+fn seq_of(args: &[AtomVal]) -> AtomRet {
+    seq(&safe_get(args, 0))
+}
+
+// Truthiness of `pred` called with `value`.
+fn test(pred: &AtomVal, value: &AtomVal) -> Result<bool, AtomError> {
+    match *pred.apply(&[value.clone()])? {
+        AtomType::Nil => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+// Sequence without its first `n` elements, realizing them.
+fn drop_items(n: i64, mut coll: AtomVal) -> AtomRet {
+    for _ in 0..n {
+        coll = match uncons(&coll)? {
+            Some((_, rest)) => rest,
+            None => return Ok(c_nil()),
+        };
+    }
+
+    Ok(coll)
+}
+
+// [func & colls] - lazy, ends with the shortest coll. A transducer without
+// colls.
+fn map(args: &[AtomVal]) -> AtomRet {
+    if args.len() == 1 {
+        return Ok(c_partial(c_func(map_xform), args.to_vec()));
+    }
+
+    Ok(c_lazy_seq(c_func(map_step), args.to_vec()))
+}
+
+fn map_step(args: &[AtomVal]) -> AtomRet {
+    let mut items = Vec::with_capacity(args.len() - 1);
+    let mut rests = vec![safe_get(args, 0)];
+    for coll in &args[1..] {
+        match uncons(coll)? {
+            Some((first, rest)) => {
+                items.push(first);
+                rests.push(rest);
+            }
+            None => return Ok(c_nil()),
+        }
+    }
+
+    Ok(c_lazy_cons(args[0].apply(&items)?, c_lazy_seq(c_func(map_step), rests)))
+}
+
+// [pred coll] - lazy. A transducer without coll.
+fn filter(args: &[AtomVal]) -> AtomRet {
+    if args.len() == 1 {
+        return Ok(c_partial(c_func(filter_xform), args.to_vec()));
+    }
+
+    Ok(c_lazy_seq(c_func(filter_step), args.to_vec()))
+}
+
+fn filter_step(args: &[AtomVal]) -> AtomRet {
+    let pred = safe_get(args, 0);
+    let mut coll = safe_get(args, 1);
+    while let Some((first, rest)) = uncons(&coll)? {
+        if test(&pred, &first)? {
+            return Ok(c_lazy_cons(first, c_lazy_seq(c_func(filter_step), vec![pred, rest])));
+        }
+        coll = rest;
+    }
+
+    Ok(c_nil())
+}
+
+// [] [end] [start end] [start end step] - lazy, infinite without an end
+fn range(args: &[AtomVal]) -> AtomRet {
+    let (start, end, step) = match args.len() {
+        0 => (c_int(0), c_nil(), c_int(1)),
+        1 => (c_int(0), args[0].clone(), c_int(1)),
+        _ => (args[0].clone(), args[1].clone(), args.get(2).cloned().unwrap_or_else(|| c_int(1))),
+    };
+
+    Ok(c_lazy_seq(c_func(range_step), vec![start, end, step]))
+}
+
+fn range_step(args: &[AtomVal]) -> AtomRet {
+    let (start, step) = (args[0].get_int()?, args[2].get_int()?);
+    let done = match *args[1] {
+        AtomType::Nil => false,
+        ref end => {
+            let end = end.get_int()?;
+            (step > 0 && start >= end) || (step < 0 && start <= end)
+        }
+    };
+    if done {
+        return Ok(c_nil());
+    }
+
+    let rest = c_lazy_seq(c_func(range_step), vec![c_int(start + step), args[1].clone(), args[2].clone()]);
+    Ok(c_lazy_cons(args[0].clone(), rest))
+}
+
+// [func value] - lazy and infinite: value, (func value), (func (func value))...
+fn iterate(args: &[AtomVal]) -> AtomRet {
+    let next = c_lazy_seq(c_func(iterate_step), vec![safe_get(args, 0), safe_get(args, 1)]);
+    Ok(c_lazy_cons(safe_get(args, 1), next))
+}
+
+fn iterate_step(args: &[AtomVal]) -> AtomRet {
+    iterate(&[args[0].clone(), args[0].apply(&[args[1].clone()])?])
+}
+
+// [value] [n value] - lazy, infinite without n
+fn repeat(args: &[AtomVal]) -> AtomRet {
+    match args.len() {
+        1 => Ok(c_lazy_seq(c_func(repeat_step), vec![args[0].clone()])),
+        _ => take(&[safe_get(args, 0), repeat(&[safe_get(args, 1)])?]),
+    }
+}
+
+fn repeat_step(args: &[AtomVal]) -> AtomRet {
+    Ok(c_lazy_cons(args[0].clone(), c_lazy_seq(c_func(repeat_step), args.to_vec())))
+}
+
+// [coll] - lazy and infinite, unless coll is empty
+fn cycle(args: &[AtomVal]) -> AtomRet {
+    Ok(c_lazy_seq(c_func(cycle_step), vec![safe_get(args, 0), safe_get(args, 0)]))
+}
+
+// [coll left] - continues with left, then coll again
+fn cycle_step(args: &[AtomVal]) -> AtomRet {
+    let step = match uncons(&args[1])? {
+        None => uncons(&args[0])?,
+        step => step,
+    };
+
+    match step {
+        Some((first, rest)) => {
+            Ok(c_lazy_cons(first, c_lazy_seq(c_func(cycle_step), vec![args[0].clone(), rest])))
+        }
+        None => Ok(c_nil()),
+    }
+}
+
+// [n coll] - lazy. A transducer without coll.
+fn take(args: &[AtomVal]) -> AtomRet {
+    if args.len() == 1 {
+        return Ok(c_partial(c_func(take_xform), args.to_vec()));
+    }
+
+    Ok(c_lazy_seq(c_func(take_step), args.to_vec()))
+}
+
+fn take_step(args: &[AtomVal]) -> AtomRet {
+    let n = safe_get(args, 0).get_int()?;
+    if n <= 0 {
+        return Ok(c_nil());
+    }
+
+    match uncons(&safe_get(args, 1))? {
+        Some((first, rest)) => Ok(c_lazy_cons(first, take(&[c_int(n - 1), rest])?)),
+        None => Ok(c_nil()),
+    }
+}
+
+// [n coll] - lazy
+fn drop(args: &[AtomVal]) -> AtomRet {
+    Ok(c_lazy_seq(c_func(drop_step), args.to_vec()))
+}
+
+fn drop_step(args: &[AtomVal]) -> AtomRet {
+    drop_items(safe_get(args, 0).get_int()?, safe_get(args, 1))
+}
+
+// [pred coll] - lazy
+fn take_while(args: &[AtomVal]) -> AtomRet {
+    Ok(c_lazy_seq(c_func(take_while_step), args.to_vec()))
+}
+
+fn take_while_step(args: &[AtomVal]) -> AtomRet {
+    let pred = safe_get(args, 0);
+    match uncons(&safe_get(args, 1))? {
+        Some((first, rest)) => {
+            if test(&pred, &first)? {
+                Ok(c_lazy_cons(first, take_while(&[pred, rest])?))
             } else {
-                Ok(c_list(&[]))
+                Ok(c_nil())
             }
         }
-        _ => Ok(c_nil()),
+        None => Ok(c_nil()),
     }
 }
 
-// [func coll]
-fn map(args: &[AtomVal]) -> AtomRet {
-    let func_atom = safe_get(args, 0);
-    let elements_atom = safe_get(args, 1);
-    let elements = elements_atom.get_list()?;
+// [pred coll] - lazy
+fn drop_while(args: &[AtomVal]) -> AtomRet {
+    Ok(c_lazy_seq(c_func(drop_while_step), args.to_vec()))
+}
 
-    let mut new_elements: Vec<AtomVal> = Vec::with_capacity(elements.iter().count());
+fn drop_while_step(args: &[AtomVal]) -> AtomRet {
+    let pred = safe_get(args, 0);
+    let mut coll = safe_get(args, 1);
+    loop {
+        coll = match uncons(&coll)? {
+            Some((ref first, ref rest)) if test(&pred, first)? => rest.clone(),
+            _ => return Ok(coll),
+        };
+    }
+}
+
+// [n coll] [n step coll] [n step pad coll] - lazy lists of n elements, each
+// starting step elements after the previous one. An incomplete last list is
+// dropped, or filled up from pad when it's given.
+fn partition(args: &[AtomVal]) -> AtomRet {
+    let (step, pad, coll) = match args.len() {
+        3 => (args[1].clone(), c_nil(), args[2].clone()),
+        len if len > 3 => (args[1].clone(), c_list(&[args[2].clone()]), args[3].clone()),
+        _ => (safe_get(args, 0), c_nil(), safe_get(args, 1)),
+    };
+
+    Ok(c_lazy_seq(c_func(partition_step), vec![safe_get(args, 0), step, pad, coll]))
+}
+
+// [n step pad coll] - pad is nil, or a list holding the pad given
+fn partition_step(args: &[AtomVal]) -> AtomRet {
+    let (n, step) = (args[0].get_int()?, args[1].get_int()?);
+    let mut items = vec![];
+    let mut coll = args[3].clone();
+    let mut next = None;
+    while (items.len() as i64) < n {
+        if items.len() as i64 == step {
+            next = Some(coll.clone());
+        }
+        match uncons(&coll)? {
+            Some((first, rest)) => {
+                items.push(first);
+                coll = rest;
+            }
+            None => break,
+        }
+    }
+
+    if (items.len() as i64) < n {
+        return match *args[2] {
+            AtomType::Nil => Ok(c_nil()),
+            ref pad => {
+                let mut pad = pad.get_list()?[0].clone();
+                while (items.len() as i64) < n {
+                    match uncons(&pad)? {
+                        Some((first, rest)) => {
+                            items.push(first);
+                            pad = rest;
+                        }
+                        None => break,
+                    }
+                }
+                Ok(c_list(&[c_list(&items)]))
+            }
+        };
+    }
+
+    let next = match next {
+        Some(next) => next,
+        None => drop_items(step - n, coll)?,
+    };
+    let rest = c_lazy_seq(c_func(partition_step),
+                          vec![args[0].clone(), args[1].clone(), args[2].clone(), next]);
+    Ok(c_lazy_cons(c_list(&items), rest))
+}
 
-    for element in elements.iter() {
-        new_elements.push(func_atom.apply(&[element.clone()])?)
+// [n coll] - lazy lists of n elements, the last one possibly shorter. A
+// transducer without coll.
+fn partition_all(args: &[AtomVal]) -> AtomRet {
+    if args.len() == 1 {
+        return Ok(c_partial(c_func(partition_all_xform), args.to_vec()));
     }
 
-    Ok(c_list(&new_elements))
+    partition(&[safe_get(args, 0), safe_get(args, 0), c_list(&[]), safe_get(args, 1)])
 }
 
-// [ func defaultValue coll]
+// [ func defaultValue coll] - stops early when func returns a reduced value
+// [func coll] [func init coll] - calls (func acc element), like the reducing
+// functions of transducers. Without init, the first element is used, or
+// (func) when coll is empty.
 fn reduce(args: &[AtomVal]) -> AtomRet {
     let func_atom = safe_get(args, 0);
-    let elements_atom = safe_get(args, 2);
-    let elements = elements_atom.get_list()?;
+    let (acc, coll) = match args.len() {
+        2 => {
+            match uncons(&args[1])? {
+                Some((first, rest)) => (first, rest),
+                None => return func_atom.apply(&[]),
+            }
+        }
+        3 => (args[1].clone(), args[2].clone()),
+        count => {
+            return Err(AtomError::InvalidArgument(format!("wrong number of args ({}) passed to: reduce",
+                                                          count)))
+        }
+    };
 
-    let last_result = elements.iter()
-        .cloned()
-        .fold(Ok(safe_get(args, 1)), |acc_result, element| {
-            let acc = acc_result?;
-            func_atom.apply(&[element, acc])
-        })?;
+    reduce_with(&func_atom, acc, &coll)
+}
 
-    Ok(last_result)
+// [value]
+fn reduced(args: &[AtomVal]) -> AtomRet {
+    Ok(c_reduced(safe_get(args, 0)))
+}
+
+fn is_reduced(args: &[AtomVal]) -> AtomRet {
+    match *safe_get(args, 0) {
+        AtomType::Reduced(_) => Ok(c_int(1)),
+        _ => Ok(c_nil()),
+    }
+}
+
+fn ensure_reduced(value: AtomVal) -> AtomVal {
+    match *value {
+        AtomType::Reduced(_) => value,
+        _ => c_reduced(value),
+    }
+}
+
+fn unreduced(value: AtomVal) -> AtomVal {
+    match *value {
+        AtomType::Reduced(ref inner) => inner.clone(),
+        _ => value,
+    }
+}
+
+// Transducers take a reducing function `rf` and return a new one, called
+// with no args for the initial value, with the result alone to complete it,
+// and with the result and an input for each step. Their state and `rf` are
+// bound as the first args of a partial function.
+
+// [func rf]
+fn map_xform(args: &[AtomVal]) -> AtomRet {
+    Ok(c_partial(c_func(map_rf), args.to_vec()))
+}
+
+// [func rf & args]
+fn map_rf(args: &[AtomVal]) -> AtomRet {
+    match args.len() {
+        2 | 3 => args[1].apply(&args[2..]),
+        _ => args[1].apply(&[args[2].clone(), args[0].apply(&args[3..])?]),
+    }
+}
+
+// [pred rf]
+fn filter_xform(args: &[AtomVal]) -> AtomRet {
+    Ok(c_partial(c_func(filter_rf), args.to_vec()))
+}
+
+// [pred rf & args]
+fn filter_rf(args: &[AtomVal]) -> AtomRet {
+    match args.len() {
+        2 | 3 => args[1].apply(&args[2..]),
+        _ if test(&args[0], &args[3])? => args[1].apply(&args[2..4]),
+        _ => Ok(args[2].clone()),
+    }
+}
+
+// [n rf] - each reducing function counts down in its own atom
+fn take_xform(args: &[AtomVal]) -> AtomRet {
+    Ok(c_partial(c_func(take_rf), vec![c_ref(safe_get(args, 0)), safe_get(args, 1)]))
+}
+
+// [left rf & args]
+fn take_rf(args: &[AtomVal]) -> AtomRet {
+    if args.len() < 4 {
+        return args[1].apply(&args[2..]);
+    }
+
+    let left = args[0].get_ref()?.deref().get_int()?;
+    args[0].get_ref()?.reset(&args[0], c_int(left - 1))?;
+    let result = if left > 0 { args[1].apply(&args[2..4])? } else { args[2].clone() };
+    if left <= 1 { Ok(ensure_reduced(result)) } else { Ok(result) }
+}
+
+// [n rf] - each reducing function collects a partition in its own atom
+fn partition_all_xform(args: &[AtomVal]) -> AtomRet {
+    let state = vec![safe_get(args, 0), c_ref(c_vec(&[])), safe_get(args, 1)];
+    Ok(c_partial(c_func(partition_all_rf), state))
+}
+
+// Items collected in the `buffer` atom, emptying it.
+fn take_buffer(buffer: &AtomVal) -> Result<Vec<AtomVal>, AtomError> {
+    let cell = buffer.get_ref()?;
+    let items = cell.deref().get_list()?.to_vec();
+    cell.reset(buffer, c_vec(&[]))?;
+    Ok(items)
+}
+
+// Adds `item` to the vector in the `buffer` atom, returning its length.
+fn push_buffer(buffer: &AtomVal, item: AtomVal) -> Result<usize, AtomError> {
+    let cell = buffer.get_ref()?;
+    let items = match *cell.deref() {
+        AtomType::Vec(ref vec) => vec.conj(item),
+        ref other => return Err(AtomError::InvalidType("Vec".to_string(), other.format(true))),
+    };
+    let len = items.len();
+    cell.reset(buffer, c_vec_of(items))?;
+    Ok(len)
+}
+
+// [n buffer rf & args] - completing flushes a partition left in the buffer
+fn partition_all_rf(args: &[AtomVal]) -> AtomRet {
+    let (buffer, rf) = (&args[1], &args[2]);
+    match args.len() {
+        3 => rf.apply(&[]),
+        4 => {
+            let items = take_buffer(buffer)?;
+            if items.is_empty() {
+                rf.apply(&[args[3].clone()])
+            } else {
+                let result = unreduced(rf.apply(&[args[3].clone(), c_list(&items)])?);
+                rf.apply(&[result])
+            }
+        }
+        _ => {
+            if push_buffer(buffer, args[4].clone())? as i64 >= args[0].get_int()? {
+                rf.apply(&[args[3].clone(), c_list(&take_buffer(buffer)?)])
+            } else {
+                Ok(args[3].clone())
+            }
+        }
+    }
+}
+
+// Folds coll with (rf result input) until it returns a reduced value.
+fn reduce_with(rf: &AtomVal, mut result: AtomVal, coll: &AtomVal) -> AtomRet {
+    for item in seq_iter(coll) {
+        result = rf.apply(&[result, item?])?;
+        if let AtomType::Reduced(ref value) = *result {
+            return Ok(value.clone());
+        }
+    }
+
+    Ok(result)
+}
+
+// [xform func coll] [xform func init coll] - init defaults to (func)
+fn transduce(args: &[AtomVal]) -> AtomRet {
+    let rf = safe_get(args, 0).apply(&[safe_get(args, 1)])?;
+    let (init, coll) = match args.len() {
+        3 => (safe_get(args, 1).apply(&[])?, args[2].clone()),
+        _ => (safe_get(args, 2), safe_get(args, 3)),
+    };
+
+    let result = reduce_with(&rf, init, &coll)?;
+    rf.apply(&[result])
+}
+
+// [to from] [to xform from] - conj of every item of from onto to
+fn into(args: &[AtomVal]) -> AtomRet {
+    match args.len() {
+        3 => transduce(&[args[1].clone(), c_func(conj), args[0].clone(), args[2].clone()]),
+        _ => reduce_with(&c_func(conj), safe_get(args, 0), &safe_get(args, 1)),
+    }
+}
+
+// [buffer & args] - reducing function collecting inputs into the buffer atom
+fn buffer_rf(args: &[AtomVal]) -> AtomRet {
+    if let Some(input) = args.get(2) {
+        push_buffer(&args[0], input.clone())?;
+    }
+
+    Ok(safe_get(args, 1))
+}
+
+// [coll] [xform coll] - lazy, transformed by xform as far as it is used
+fn sequence(args: &[AtomVal]) -> AtomRet {
+    if args.len() < 2 {
+        return match *seq(&safe_get(args, 0))? {
+            AtomType::Nil => Ok(c_list(&[])),
+            _ => Ok(safe_get(args, 0)),
+        };
+    }
+
+    let buffer = c_ref(c_vec(&[]));
+    let rf = args[0].apply(&[c_partial(c_func(buffer_rf), vec![buffer.clone()])])?;
+    Ok(c_lazy_seq(c_func(sequence_step), vec![rf, buffer, args[1].clone()]))
+}
+
+// [rf buffer coll] - feeds coll to rf until it added something to the buffer
+fn sequence_step(args: &[AtomVal]) -> AtomRet {
+    let (rf, buffer) = (&args[0], &args[1]);
+    let mut coll = args[2].clone();
+    let mut done = false;
+    while buffer.get_ref()?.deref().get_list()?.is_empty() {
+        match uncons(&coll)? {
+            Some((first, rest)) => {
+                coll = rest;
+                if let AtomType::Reduced(_) = *rf.apply(&[c_nil(), first])? {
+                    done = true;
+                    break;
+                }
+            }
+            None => {
+                done = true;
+                break;
+            }
+        }
+    }
+    if done {
+        rf.apply(&[c_nil()])?;
+    }
+
+    let mut result = if done {
+        c_nil()
+    } else {
+        c_lazy_seq(c_func(sequence_step), vec![rf.clone(), buffer.clone(), coll])
+    };
+    for item in take_buffer(buffer)?.into_iter().rev() {
+        result = c_lazy_cons(item, result);
+    }
+
+    Ok(result)
+}
+
+// [& fns] - function calling the last of fns with its args, then each one
+// before it with the result
+fn comp(args: &[AtomVal]) -> AtomRet {
+    match args.len() {
+        0 => Ok(c_func(identity)),
+        1 => Ok(args[0].clone()),
+        _ => Ok(c_partial(c_func(comp_step), vec![c_list(args)])),
+    }
+}
+
+// [fns & args]
+fn comp_step(args: &[AtomVal]) -> AtomRet {
+    let fns = args[0].get_list()?;
+    let (last, fns) = fns.split_last().expect("comp of at least two functions");
+    let mut result = last.apply(&args[1..])?;
+    for func in fns.iter().rev() {
+        result = func.apply(&[result])?;
+    }
+
+    Ok(result)
+}
+
+fn identity(args: &[AtomVal]) -> AtomRet {
+    Ok(safe_get(args, 0))
+}
+
+// [prefix?]
+fn gensym(args: &[AtomVal]) -> AtomRet {
+    match *safe_get(args, 0) {
+        AtomType::Nil => Ok(c_gensym("G__")),
+        AtomType::Str(ref prefix) => Ok(c_gensym(prefix)),
+        ref v => Err(AtomError::InvalidType("Str".to_string(), v.format(true))),
+    }
+}
+
+// [value]
+fn atom(args: &[AtomVal]) -> AtomRet {
+    Ok(c_ref(safe_get(args, 0)))
+}
+
+fn is_atom(args: &[AtomVal]) -> AtomRet {
+    match *safe_get(args, 0) {
+        AtomType::Ref(_) => Ok(c_int(1)),
+        _ => Ok(c_nil()),
+    }
+}
+
+// [atom-or-var]
+fn deref(args: &[AtomVal]) -> AtomRet {
+    match *safe_get(args, 0) {
+        AtomType::Var(ref var) => Ok(var.deref()),
+        AtomType::Reduced(ref value) => Ok(value.clone()),
+        ref value => Ok(value.get_ref()?.deref()),
+    }
+}
+
+// [atom value]
+fn reset(args: &[AtomVal]) -> AtomRet {
+    let atom = safe_get(args, 0);
+    let value = safe_get(args, 1);
+    atom.get_ref()?.reset(&atom, value.clone())?;
+    Ok(value)
+}
+
+// [atom func & args] - sets atom to (func current args...)
+fn swap(args: &[AtomVal]) -> AtomRet {
+    let atom = safe_get(args, 0);
+    let cell = atom.get_ref()?;
+
+    let mut func_args = vec![cell.deref()];
+    func_args.extend(args.iter().skip(2).cloned());
+    let value = safe_get(args, 1).apply(&func_args)?;
+
+    cell.reset(&atom, value.clone())?;
+    Ok(value)
+}
+
+// [atom old new] - sets atom to new only when it still holds old
+fn compare_and_set(args: &[AtomVal]) -> AtomRet {
+    let atom = safe_get(args, 0);
+    let cell = atom.get_ref()?;
+    if cell.deref() != safe_get(args, 1) {
+        return Ok(c_nil());
+    }
+
+    cell.reset(&atom, safe_get(args, 2))?;
+    Ok(c_int(1))
+}
+
+// [atom-or-var key func]
+fn add_watch(args: &[AtomVal]) -> AtomRet {
+    let reference = safe_get(args, 0);
+    reference.get_watches()?.add(safe_get(args, 1), safe_get(args, 2));
+    Ok(reference)
+}
+
+// [atom-or-var key]
+fn remove_watch(args: &[AtomVal]) -> AtomRet {
+    let reference = safe_get(args, 0);
+    reference.get_watches()?.remove(&safe_get(args, 1));
+    Ok(reference)
+}
+
+// [] - frees unreachable envs and atoms, returning how many
+fn collect(_args: &[AtomVal]) -> AtomRet {
+    Ok(c_int(gc::collect() as i64))
+}
+
+fn heap_stats(_args: &[AtomVal]) -> AtomRet {
+    let stats = gc::stats();
+    let mut map = AtomMap::new();
+    map.insert(c_keyword("envs"), c_int(stats.envs as i64));
+    map.insert(c_keyword("atoms"), c_int(stats.atoms as i64));
+    map.insert(c_keyword("collections"), c_int(stats.collections as i64));
+    map.insert(c_keyword("freed"), c_int(stats.freed as i64));
+    Ok(c_map(map))
+}
+
+fn is_realized(args: &[AtomVal]) -> AtomRet {
+    match *safe_get(args, 0) {
+        AtomType::LazySeq(ref seq) if !seq.is_realized() => Ok(c_nil()),
+        _ => Ok(c_int(1)),
+    }
+}
+
+// Lazy sequences are equal to lists with the same elements.
+fn equal(a: &AtomVal, b: &AtomVal) -> Result<bool, AtomError> {
+    match (&**a, &**b) {
+        (&AtomType::LazySeq(_), &AtomType::List(_)) |
+        (&AtomType::List(_), &AtomType::LazySeq(_)) => Ok(seq_items(a)? == seq_items(b)?),
+        _ => Ok(a == b),
+    }
 }
 
 fn partialeq(args: &[AtomVal]) -> AtomRet {
     for (i, arg) in args.iter().enumerate() {
         if let Some(next_arg) = args.get(i + 1) {
-            if next_arg != arg {
+            if !equal(arg, next_arg)? {
                 return Ok(c_nil());
             }
         }
@@ -142,75 +1040,347 @@ fn partialeq(args: &[AtomVal]) -> AtomRet {
 }
 
 
-fn format_args(args: &[AtomVal], format: bool) -> String {
-    args.iter()
-        .map(|ref v| v.format(format))
+// Strings are printed without quotes, like in Clojure's println. Fails when
+// realizing a lazy sequence in args fails.
+fn format_args(args: &[AtomVal], format: bool) -> Result<String, AtomError> {
+    for arg in args {
+        realize_printed(arg)?;
+    }
+
+    Ok(args.iter()
+        .map(|ref v| match ***v {
+            AtomType::Str(ref str) if !format => str.clone(),
+            _ => v.format(format),
+        })
         .collect::<Vec<_>>()
-        .join(" ")
+        .join(" "))
+}
+
+// Writes `text` to the current value of *out*: stdout, :stderr, or an atom
+// whose string gets `text` appended. An atom holding a vector gets `text`
+// added as a new piece instead, which `with-out-str` joins once at the end.
+fn write_out(text: &str) -> Result<(), AtomError> {
+    let out = dynamic::lookup(OUT).unwrap_or_else(c_nil);
+    match *out {
+        AtomType::Keyword(ref name) if name.as_str() == "stderr" => eprint!("{}", text),
+        AtomType::Ref(ref cell) => {
+            let written = match *cell.deref() {
+                AtomType::Vec(ref pieces) => c_vec_of(pieces.conj(c_string(text))),
+                AtomType::Str(ref str) => c_string(&format!("{}{}", str, text)),
+                AtomType::Nil => c_string(text),
+                ref other => return Err(AtomError::InvalidType("Str".to_string(), other.format(true))),
+            };
+            cell.reset(&out, written)?;
+        }
+        _ => print!("{}", text),
+    }
+
+    Ok(())
+}
+
+// [pieces] - strings written into a vector by `write_out`, joined
+fn out_str(args: &[AtomVal]) -> AtomRet {
+    let pieces = safe_get(args, 0);
+    let mut joined = String::new();
+    for piece in pieces.get_list()?.iter() {
+        joined.push_str(piece.get_str()?);
+    }
+
+    Ok(c_string(&joined))
 }
 
 fn println(args: &[AtomVal]) -> AtomRet {
-    println!("{}", format_args(&args, false));
-    Ok(safe_get(args, 0))
+    write_out(&format!("{}\n", format_args(&args, false)?))?;
+    Ok(c_nil())
 }
 
 fn print(args: &[AtomVal]) -> AtomRet {
-    print!("{}", format_args(&args, false));
-    Ok(safe_get(args, 0))
+    write_out(&format_args(&args, false)?)?;
+    Ok(c_nil())
 }
 
 fn _println(args: &[AtomVal]) -> AtomRet {
-    println!("{}", format_args(&args, true));
-    Ok(safe_get(args, 0))
+    write_out(&format!("{}\n", format_args(&args, true)?))?;
+    Ok(c_nil())
 }
 
 fn _print(args: &[AtomVal]) -> AtomRet {
-    print!("{}", format_args(&args, true));
-    Ok(safe_get(args, 0))
+    write_out(&format_args(&args, true)?)?;
+    Ok(c_nil())
 }
 
 
-#[allow(unused_must_use)]
+// User namespace env with the bundled prelude, for tests.
+#[cfg(test)]
 pub fn build() -> Env {
-    let env = c_env(None);
-
-    let mut f = File::open("src/core.clrs").expect("core.clrs has to be openable");
-    let mut s = String::new();
-    f.read_to_string(&mut s).expect("Couldn't read core.clrs");
-
-    eval_str(s.as_str(), &env).expect("Problem loading core.clrs into ENV");
-
-    env_set(&env, &c_symbol("print"), c_func(print));
-    env_set(&env, &c_symbol("println"), c_func(println));
-    env_set(&env, &c_symbol("_print"), c_func(_print));
-    env_set(&env, &c_symbol("_println"), c_func(_println));
-    env_set(&env, &c_symbol("+"), c_func(add));
-    env_set(&env, &c_symbol("-"), c_func(sub));
-    env_set(&env, &c_symbol("*"), c_func(mul));
-    env_set(&env, &c_symbol("/"), c_func(div));
-    env_set(&env, &c_symbol("cons"), c_func(cons));
-    env_set(&env, &c_symbol("list"), c_func(list));
-    env_set(&env, &c_symbol("list?"), c_func(is_list));
-    env_set(&env, &c_symbol("nil?"), c_func(is_nil));
-    env_set(&env, &c_symbol("nth"), c_func(nth));
-    env_set(&env, &c_symbol("rest"), c_func(rest));
-    env_set(&env, &c_symbol("count"), c_func(count));
-    env_set(&env, &c_symbol("map"), c_func(map));
-    env_set(&env, &c_symbol("reduce"), c_func(reduce));
+    build_with_prelude(None).expect("Problem loading core.clrs into ENV")
+}
+
+// Builds the core namespace, evaluating the prelude from `path` instead of
+// the bundled core.clrs when given, and returns the env of the user namespace.
+pub fn build_with_prelude(path: Option<&str>) -> Result<Env, AtomError> {
+    let env = find_or_create(&c_registry(), CORE_NS);
+
+    env_set(&env, &c_symbol("print"), c_func(print))?;
+    env_set(&env, &c_symbol("println"), c_func(println))?;
+    env_set(&env, &c_symbol("_print"), c_func(_print))?;
+    env_set(&env, &c_symbol("_println"), c_func(_println))?;
+    env_set(&env, &c_symbol("_out-str"), c_func(out_str))?;
+    env_set(&env, &c_symbol("+"), c_func(add))?;
+    env_set(&env, &c_symbol("-"), c_func(sub))?;
+    env_set(&env, &c_symbol("*"), c_func(mul))?;
+    env_set(&env, &c_symbol("/"), c_func(div))?;
+    env_set(&env, &c_symbol("cons"), c_func(cons))?;
+    env_set(&env, &c_symbol("list"), c_func(list))?;
+    env_set(&env, &c_symbol("concat"), c_func(concat))?;
+    env_set(&env, &c_symbol("vec"), c_func(vec))?;
+    env_set(&env, &c_symbol("hash-map"), c_func(hash_map))?;
+    env_set(&env, &c_symbol("hash-set"), c_func(hash_set))?;
+    env_set(&env, &c_symbol("set"), c_func(set))?;
+    env_set(&env, &c_symbol("conj"), c_func(conj))?;
+    env_set(&env, &c_symbol("assoc"), c_func(assoc))?;
+    env_set(&env, &c_symbol("dissoc"), c_func(dissoc))?;
+    env_set(&env, &c_symbol("disj"), c_func(disj))?;
+    env_set(&env, &c_symbol("get"), c_func(get))?;
+    env_set(&env, &c_symbol("contains?"), c_func(contains))?;
+    env_set(&env, &c_symbol("list?"), c_func(is_list))?;
+    env_set(&env, &c_symbol("nil?"), c_func(is_nil))?;
+    env_set(&env, &c_symbol("empty?"), c_func(is_empty))?;
+    env_set(&env, &c_symbol("seq"), c_func(seq_of))?;
+    env_set(&env, &c_symbol("first"), c_func(first))?;
+    env_set(&env, &c_symbol("nth"), c_func(nth))?;
+    env_set(&env, &c_symbol("rest"), c_func(rest))?;
+    env_set(&env, &c_symbol("count"), c_func(count))?;
+    env_set(&env, &c_symbol("map"), c_func(map))?;
+    env_set(&env, &c_symbol("filter"), c_func(filter))?;
+    env_set(&env, &c_symbol("reduce"), c_func(reduce))?;
+    env_set(&env, &c_symbol("reduced"), c_func(reduced))?;
+    env_set(&env, &c_symbol("reduced?"), c_func(is_reduced))?;
+    env_set(&env, &c_symbol("identity"), c_func(identity))?;
+    env_set(&env, &c_symbol("comp"), c_func(comp))?;
+    env_set(&env, &c_symbol("gensym"), c_func(gensym))?;
+    env_set(&env, &c_symbol("type"), c_func(multi::type_of))?;
+
+    // lazy sequences
+    env_set(&env, &c_symbol("range"), c_func(range))?;
+    env_set(&env, &c_symbol("iterate"), c_func(iterate))?;
+    env_set(&env, &c_symbol("repeat"), c_func(repeat))?;
+    env_set(&env, &c_symbol("cycle"), c_func(cycle))?;
+    env_set(&env, &c_symbol("take"), c_func(take))?;
+    env_set(&env, &c_symbol("drop"), c_func(drop))?;
+    env_set(&env, &c_symbol("take-while"), c_func(take_while))?;
+    env_set(&env, &c_symbol("drop-while"), c_func(drop_while))?;
+    env_set(&env, &c_symbol("partition"), c_func(partition))?;
+    env_set(&env, &c_symbol("partition-all"), c_func(partition_all))?;
+    env_set(&env, &c_symbol("realized?"), c_func(is_realized))?;
+
+    // transducers
+    env_set(&env, &c_symbol("transduce"), c_func(transduce))?;
+    env_set(&env, &c_symbol("into"), c_func(into))?;
+    env_set(&env, &c_symbol("sequence"), c_func(sequence))?;
+
+    // atoms
+    env_set(&env, &c_symbol("atom"), c_func(atom))?;
+    env_set(&env, &c_symbol("atom?"), c_func(is_atom))?;
+    env_set(&env, &c_symbol("deref"), c_func(deref))?;
+    env_set(&env, &c_symbol("reset!"), c_func(reset))?;
+    env_set(&env, &c_symbol("swap!"), c_func(swap))?;
+    env_set(&env, &c_symbol("compare-and-set!"), c_func(compare_and_set))?;
+    env_set(&env, &c_symbol("add-watch"), c_func(add_watch))?;
+    env_set(&env, &c_symbol("remove-watch"), c_func(remove_watch))?;
+
+    // memory
+    env_set(&env, &c_symbol("gc"), c_func(collect))?;
+    env_set(&env, &c_symbol("heap-stats"), c_func(heap_stats))?;
 
     // predicates
-    env_set(&env, &c_symbol("="), c_func(partialeq));
+    env_set(&env, &c_symbol("="), c_func(partialeq))?;
     // env_set(&env, &c_symbol("="), c_func(partialeq));
 
-    env
+    // modules
+    env_set(&env, &c_symbol("*load-path*"), c_list(&[c_string(".")]))?;
+    env_set(&env, &c_symbol("*loaded-libs*"), c_list(&[]))?;
+
+    match path {
+        None => {
+            eval_str_all(PRELUDE, &env)?;
+        }
+        Some(path) => {
+            load_file(path, &env)?;
+        }
+    }
+
+    switch(&env, USER_NS)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::add;
-    use data::c_int;
+    use super::{add, build, build_with_prelude};
+    use data::{c_int, realize_printed};
+    use eval::eval_str_all;
     use test::Bencher;
 
+    fn eval(str: &str) -> String {
+        match eval_str_all(str, &build()).and_then(|value| realize_printed(&value).map(|_| value)) {
+            Ok(value) => format!("{}", value),
+            Err(err) => format!("{}", err),
+        }
+    }
+
+    #[test]
+    fn missing_prelude_override_is_an_error() {
+        assert!(build_with_prelude(Some("does/not/exist.clrs")).is_err());
+    }
+
+    #[test]
+    fn persistent_lists() {
+        assert_eq!(eval("(def xs (cons 1 (cons 2 (list 3)))) (list xs (rest xs) (count xs) (nth xs 2))"),
+                   "((1 2 3) (2 3) 3 3)");
+        assert_eq!(eval("(def xs (list 2 3)) (list (cons 1 xs) (cons 0 (rest xs)) xs)"),
+                   "((1 2 3) (0 3) (2 3))");
+        assert_eq!(eval("(list (concat [0] nil (list 1 2) [3]) (concat) (cons 1 nil) (rest nil))"),
+                   "((0 1 2 3) () (1) nil)");
+        assert_eq!(eval("(= (cons 1 (list 2)) (list 1 2))"), "1");
+    }
+
+    #[test]
+    fn persistent_collections() {
+        assert_eq!(eval("(list (conj [1 2] 3 4) (conj (list 1) 2) (conj {:a 1} [:b 2]) (conj #{1} 2 1))"),
+                   "([1 2 3 4] (2 1) {:a 1 :b 2} #{1 2})");
+        assert_eq!(eval("(list (assoc {:a 1} :b 2 :a 3) (assoc [1 2] 0 :x 2 :y) (dissoc {:a 1 :b 2} :a))"),
+                   "({:a 3 :b 2} [:x 2 :y] {:b 2})");
+        assert_eq!(eval("(list (get [1 2] 5 :none) (get #{:a} :a) (contains? {:a nil} :a) (disj #{1 2} 1))"),
+                   "(:none :a 1 #{2})");
+        assert_eq!(eval("(loop [i 0 m {}] (if (= i 1000) (list (count m) (get m 500)) \
+                         (recur (+ i 1) (assoc m i (* i 2)))))"),
+                   "(1000 1000)");
+        assert_eq!(eval("(list (= {:a 1 :b 2} {:b 2 :a 1}) (= (set [1 2]) (hash-set 2 1)))"), "(1 1)");
+    }
+
+    #[test]
+    fn sequences_over_every_collection() {
+        assert_eq!(eval("(list (count nil) (count \"héllo\") (count {:a 1}) (count #{1 2}) (count (range 3)))"),
+                   "(0 5 1 2 3)");
+        assert_eq!(eval("(list (first {:a 1}) (rest \"abc\") (nth #{:x} 0) (seq []) (seq [1]) (first nil))"),
+                   "([:a 1] (\"b\" \"c\") :x nil (1) nil)");
+        assert_eq!(eval("(list (map inc #{1}) (map first {:a 1 :b 2}) (filter (fn [c] (= c \"b\")) \"abc\"))"),
+                   "((2) (:a :b) (\"b\"))");
+        assert_eq!(eval("(list (reduce + 0 [1 2 3]) (reduce + 0 #{4}) (reduce + 0 nil) (vec \"ab\") (concat {:a 1} \"c\"))"),
+                   "(6 4 0 [\"a\" \"b\"] ([:a 1] \"c\"))");
+        assert_eq!(eval("(list (empty? nil) (empty? \"\") (empty? {}) (empty? (range)) (second [1 2]))"),
+                   "(1 1 1 nil 2)");
+        assert_eq!(eval("(list (empty? []) (empty? #{1}) (empty? (filter nil? [1]))
+                               (first [3 4]) (first #{5}) (first \"hé\"))"),
+                   "(1 nil 1 3 5 \"h\")");
+        assert_eq!(eval("(let [[a b] \"xy\" [[k v]] {:k 1}] (list a b k v))"), "(\"x\" \"y\" :k 1)");
+    }
+
+    #[test]
+    fn lazy_sequences() {
+        assert_eq!(eval("(list (take 3 (range)) (range 2 5) (range 5 0 (- 0 2)) (take 3 (iterate inc 5)))"),
+                   "((0 1 2) (2 3 4) (5 3 1) (5 6 7))");
+        assert_eq!(eval("(list (repeat 2 :a) (take 5 (cycle [1 2])) (drop 2 [1 2 3]) (cycle nil))"),
+                   "((:a :a) (1 2 1 2 1) (3) ())");
+        assert_eq!(eval("(list (take-while (fn [x] (not (= x 3))) (range)) (take 2 (drop-while (fn [x] (not (= x 3))) (range))))"),
+                   "((0 1 2) (3 4))");
+        assert_eq!(eval("(list (partition 2 (range 5)) (partition 2 1 [1 2 3]) (partition 2 2 [:x] [1 2 3]))"),
+                   "(((0 1) (2 3)) ((1 2) (2 3)) ((1 2) (3 :x)))");
+        assert_eq!(eval("(list (map + [1 2 3] (range)) (filter (fn [x] (= (* x x) 16)) (range 10)))"),
+                   "((1 3 5) (4))");
+        assert_eq!(eval("(nth (map inc (range)) 100000)"), "100001");
+        assert_eq!(eval("(count (range 100000))"), "100000");
+        assert!(eval("(range 200)").ends_with(" 98 99 ...)"));
+    }
+
+    #[test]
+    fn printing_lazy_seq_returns_its_error() {
+        assert_eq!(eval("(with-out-str (println (map (fn [x] (undefined-fn x)) [1 2])))"),
+                   "undefined symbol: undefined-fn");
+        assert_eq!(eval("(with-out-str (print (map inc [1 2])))"), "\"(2 3)\"");
+    }
+
+    #[test]
+    fn reduce_without_init() {
+        assert_eq!(eval("(list (reduce + [1 2 3]) (reduce + (range 5)) (reduce + [7]) (reduce list []))"),
+                   "(6 10 7 ())");
+        assert_eq!(eval("(reduce + 0 [1] [2])"), "invalid argument: wrong number of args (4) passed to: reduce");
+    }
+
+    #[test]
+    fn reduced_stops_reduce() {
+        assert_eq!(eval("(reduce (fn [acc x] (if (= x 3) (reduced acc) (+ acc x))) 0 (range))"), "3");
+        assert_eq!(eval("(list (reduce conj [] '(1 2)) (reduce (fn [acc x] (cons x acc)) '() [1 2]))"),
+                   "([1 2] (2 1))");
+        assert_eq!(eval("(list (reduced? (reduced 1)) (reduced? 1) @(reduced 2) (reduced :a))"),
+                   "(1 nil 2 #reduced(:a))");
+    }
+
+    #[test]
+    fn transducers() {
+        assert_eq!(eval("(def xf (comp (filter (fn [x] (= 0 (- x (* 2 (/ x 2)))))) (map inc) (take 3)))
+                         (list (transduce xf + 0 (range)) (into [] xf (range 100)) (sequence xf (range)))"),
+                   "(9 [1 3 5] (1 3 5))");
+        assert_eq!(eval("(list (into [] (partition-all 2) [1 2 3]) (partition-all 2 [1 2 3]) (into #{} [1 1 2]))"),
+                   "([(1 2) (3)] ((1 2) (3)) #{1 2})");
+        assert_eq!(eval("(list (transduce (map inc) conj [1 2]) (transduce (map inc) + [1 2]) (into {} [[:a 1]]))"),
+                   "([2 3] 5 {:a 1})");
+        assert_eq!(eval("(list (sequence nil) ((comp) 4) ((comp inc inc) 1))"), "(() 4 3)");
+        assert_eq!(eval("(def calls (atom 0))
+                         (first (sequence (map (fn [x] (swap! calls inc) x)) (range)))
+                         @calls"),
+                   "1");
+    }
+
+    #[test]
+    fn lazy_seq_is_realized_once() {
+        assert_eq!(eval("(def calls (atom 0))
+                         (def xs (lazy-seq (swap! calls inc) (list 1 2)))
+                         (def before (realized? xs))
+                         (list before (first xs) (rest xs) @calls (realized? xs))"),
+                   "(nil 1 (2) 1 1)");
+        assert_eq!(eval("(defn nums [n] (lazy-seq (cons n (nums (+ n 1))))) (take 3 (nums 7))"), "(7 8 9)");
+        assert_eq!(eval("(list (= (map inc [1 2]) (list 2 3)) (cons 0 (lazy-seq [1])) (lazy-seq nil))"),
+                   "(1 (0 1) ())");
+    }
+
+    #[test]
+    fn atoms() {
+        assert_eq!(eval("(def a (atom 1)) (reset! a 2) @a"), "2");
+        assert_eq!(eval("(def a (atom 1)) (swap! a + 2 3) (deref a)"), "6");
+        assert_eq!(eval("(def a (atom 1)) (list (compare-and-set! a 2 3) @a)"), "(nil 1)");
+        assert_eq!(eval("(def a (atom 1)) (list (compare-and-set! a 1 3) @a)"), "(1 3)");
+        assert_eq!(eval("(atom [1])"), "#atom([1])");
+        assert_eq!(eval("(= (atom 1) (atom 1))"), "nil");
+    }
+
+    #[test]
+    fn print_functions_show_strings() {
+        assert_eq!(eval("(with-out-str (println \"a\" 1 [\"b\"]))"), "\"a 1 [\\\"b\\\"]\\n\"");
+        assert_eq!(eval("(with-out-str (_println \"a\") (_print \"b\"))"), "\"Str(\\\"a\\\")\\nStr(\\\"b\\\")\"");
+        assert_eq!(eval("(binding [*out* (atom \"\")] (list (print 1) (println 2) (_print 3) (_println 4)))"),
+                   "(nil nil nil nil)");
+    }
+
+    #[test]
+    fn out_can_be_rebound() {
+        assert_eq!(eval("(with-out-str (print 1 \"a\") (println [2]))"), "\"1 a[2]\\n\"");
+        assert_eq!(eval("(def out (atom nil)) (binding [*out* out] (print 2) (print 3)) @out"), "\"23\"");
+        assert_eq!(eval("(def out (atom [])) (binding [*out* out] (print 2) (print 3)) @out"), "[\"2\" \"3\"]");
+    }
+
+    #[test]
+    fn atom_watches() {
+        assert_eq!(eval("(def log (atom ()))
+                         (def a (atom 1))
+                         (add-watch a :log (fn [k r old new] (swap! log (fn [l] (cons (list k old new) l)))))
+                         (swap! a inc)
+                         (reset! a 5)
+                         (remove-watch a :log)
+                         (reset! a 6)
+                         @log"),
+                   "((:log 2 5) (:log 1 2))");
+    }
+
     #[bench]
     fn bench_adding(b: &mut Bencher) {
         let args = [c_int(1), c_int(1)];
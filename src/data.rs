@@ -1,30 +1,209 @@
 use std::fmt::*;
 use std::rc::Rc;
+use std::cell::{Cell, RefCell};
 use std::result;
-use eval::eval;
-use env::{c_env, env_bind, env_set, Env};
+use std::borrow::Cow;
+use eval::eval_expanded;
+use dynamic;
+use gc;
+use lazy::{LazySeq, PRINT_LIMIT};
+use list::AtomList;
+use map::AtomSet;
+use multi::AtomMulti;
+use record::AtomRecord;
+use vector::AtomVec;
+use env::{c_env, env_destructure, env_get, Env, WeakEnv};
+pub use map::AtomMap;
 
 #[allow(dead_code)]
 #[derive(Debug, PartialEq)]
 pub enum AtomType {
     Nil,
     Int(i64),
+    Str(String),
     Symbol(Rc<String>),
-    List(Vec<AtomVal>),
-    Vec(Vec<AtomVal>),
+    Keyword(Rc<String>),
+    List(AtomList),
+    Vec(AtomVec),
+    Map(AtomMap),
+    Set(AtomSet),
+    LazySeq(LazySeq),
+    Record(AtomRecord), // instance of a type defined by defrecord
     Func(AtomFunc),
     AFunc(AFuncData), // user defined function
+    Partial(AtomVal, Vec<AtomVal>), // function called with these args first
+    Multi(AtomMulti), // multimethod or protocol method
+    Reduced(AtomVal), // value ending a reduce early
+    Ref(AtomRef), // mutable cell created by `atom`
+    Var(AtomVar),
 }
 
 
+// Params and body of one arity of a function.
 #[derive(Clone, Debug, PartialEq)]
-pub struct AFuncData {
+pub struct FnClause {
+    pub params: AtomVal,
     pub exp: AtomVal,
+    // number of params before `&`, and the pattern following it
+    pub arity: usize,
+    pub rest: Option<AtomVal>,
+}
+
+impl FnClause {
+    pub fn new(params: AtomVal, exp: AtomVal) -> FnClause {
+        let (arity, rest) = match params.get_list() {
+            Ok(list) => {
+                match list.iter().position(|v| v.is_symbol("&")) {
+                    Some(index) => (index, list.get(index + 1).cloned()),
+                    None => (list.len(), None),
+                }
+            }
+            Err(_) => (0, None),
+        };
+
+        FnClause { params, exp, arity, rest }
+    }
+
+    fn accepts(&self, count: usize) -> bool {
+        count == self.arity || (self.rest.is_some() && count > self.arity)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AFuncData {
+    pub clauses: Rc<Vec<FnClause>>,
     pub env: Env,
-    pub params: AtomVal,
+    pub name: Option<Rc<String>>,
+    // docstring and attribute map given to defn or defmacro
+    pub meta: AtomMap,
     pub is_macro: bool
 }
 
+impl AFuncData {
+    // Fixed arities win over a variadic one accepting the same count.
+    fn clause(&self, count: usize) -> Option<&FnClause> {
+        self.clauses
+            .iter()
+            .find(|clause| clause.rest.is_none() && clause.arity == count)
+            .or_else(|| self.clauses.iter().find(|clause| clause.accepts(count)))
+    }
+
+    pub fn doc(&self) -> Option<&str> {
+        self.meta
+            .iter()
+            .find(|&&(ref key, _)| key.is_keyword("doc"))
+            .and_then(|&(_, ref doc)| doc.get_str().ok())
+    }
+}
+
+// Functions called as (key ref old new) whenever a reference changes.
+#[derive(Debug, Default)]
+pub struct Watches(RefCell<Vec<(AtomVal, AtomVal)>>);
+
+impl Watches {
+    pub fn add(&self, key: AtomVal, watch: AtomVal) {
+        self.remove(&key);
+        self.0.borrow_mut().push((key, watch));
+    }
+
+    pub fn remove(&self, key: &AtomVal) {
+        self.0.borrow_mut().retain(|&(ref watch_key, _)| watch_key != key);
+    }
+
+    // Keys and functions, None while the watches are being changed.
+    pub fn references(&self) -> Option<Vec<AtomVal>> {
+        let watches = self.0.try_borrow().ok()?;
+        Some(watches.iter().flat_map(|&(ref key, ref watch)| vec![key.clone(), watch.clone()]).collect())
+    }
+
+    // `this` is the value holding the reference, passed on to the watches.
+    pub fn notify(&self, this: &AtomVal, old: &AtomVal, new: &AtomVal) -> result::Result<(), AtomError> {
+        let watches = self.0.borrow().clone();
+        for (key, watch) in watches {
+            watch.apply(&[key, this.clone(), old.clone(), new.clone()])?;
+        }
+
+        Ok(())
+    }
+}
+
+// Mutable cell created by `atom`.
+#[derive(Debug)]
+pub struct AtomRef {
+    value: RefCell<AtomVal>,
+    pub watches: Watches,
+}
+
+impl AtomRef {
+    pub fn deref(&self) -> AtomVal {
+        self.value.borrow().clone()
+    }
+
+    pub fn reset(&self, this: &AtomVal, value: AtomVal) -> result::Result<(), AtomError> {
+        let old = self.value.replace(value.clone());
+        self.watches.notify(this, &old, &value)
+    }
+
+    // Value and watches, None while any of them is being changed.
+    pub fn references(&self) -> Option<Vec<AtomVal>> {
+        let mut references = self.watches.references()?;
+        references.push(self.value.try_borrow().ok()?.clone());
+        Some(references)
+    }
+
+    // Drops the value and watches, breaking the cycles the cell is part of.
+    pub fn clear(&self) {
+        let value = self.value.replace(c_nil());
+        let watches = self.watches.0.replace(vec![]);
+        drop(value);
+        drop(watches);
+    }
+}
+
+// Cells are only equal to themselves.
+impl PartialEq for AtomRef {
+    fn eq(&self, other: &AtomRef) -> bool {
+        self as *const AtomRef == other as *const AtomRef
+    }
+}
+
+// Global definition made by `def`, reading its value from the namespace env
+// it lives in. Watches are told about redefinitions. The env binds a dynamic
+// var to the var itself, which holds the root value used outside `binding`.
+#[derive(Debug)]
+pub struct AtomVar {
+    pub name: String,
+    env: WeakEnv,
+    root: RefCell<Option<AtomVal>>,
+    pub watches: Watches,
+}
+
+impl AtomVar {
+    pub fn is_dynamic(&self) -> bool {
+        self.root.borrow().is_some()
+    }
+
+    // Makes the var dynamic, returning the previous root value.
+    pub fn set_root(&self, value: AtomVal) -> Option<AtomVal> {
+        self.root.replace(Some(value))
+    }
+
+    pub fn deref(&self) -> AtomVal {
+        if let Some(ref root) = *self.root.borrow() {
+            return dynamic::lookup(&self.name).unwrap_or_else(|| root.clone());
+        }
+
+        let name = c_symbol(self.name.rsplit('/').next().unwrap_or(&self.name));
+        self.env.upgrade().and_then(|env| env_get(&env, &name)).unwrap_or_else(c_nil)
+    }
+}
+
+impl PartialEq for AtomVar {
+    fn eq(&self, other: &AtomVar) -> bool {
+        self as *const AtomVar == other as *const AtomVar
+    }
+}
+
 pub struct AtomFunc(fn(&[AtomVal]) -> AtomRet);
 
 impl Debug for AtomFunc {
@@ -46,12 +225,69 @@ impl Display for AtomType {
     }
 }
 
+// Elements of a lazy sequence up to the print limit, realizing them, with
+// `...` when there are more.
+// Prints the elements realized already, see realize_printed.
+fn format_lazy(seq: &LazySeq, with_type: bool) -> String {
+    let (items, more) = seq.realized_prefix(PRINT_LIMIT);
+    let mut items = items.iter().map(|v| v.format(with_type)).collect::<Vec<_>>();
+    if more {
+        items.push("...".to_string());
+    }
+    items.join(" ")
+}
+
+// Realizes the lazy sequences in value as far as printing shows them, so an
+// error raised while realizing them is returned instead of being printed.
+pub fn realize_printed(value: &AtomVal) -> result::Result<(), AtomError> {
+    match **value {
+        AtomType::LazySeq(ref seq) => {
+            for item in seq.prefix(PRINT_LIMIT)?.0 {
+                realize_printed(&item)?;
+            }
+        }
+        AtomType::List(ref list) => {
+            for item in list.iter() {
+                realize_printed(item)?;
+            }
+        }
+        AtomType::Vec(ref vec) => {
+            for item in vec.iter() {
+                realize_printed(item)?;
+            }
+        }
+        AtomType::Set(ref set) => {
+            for item in set.iter() {
+                realize_printed(item)?;
+            }
+        }
+        AtomType::Map(ref map) => {
+            for &(ref key, ref value) in map.iter() {
+                realize_printed(key)?;
+                realize_printed(value)?;
+            }
+        }
+        AtomType::Record(ref record) => {
+            for &(ref key, ref value) in record.entries().iter() {
+                realize_printed(key)?;
+                realize_printed(value)?;
+            }
+        }
+        AtomType::Reduced(ref value) => realize_printed(value)?,
+        AtomType::Ref(ref cell) => realize_printed(&cell.deref())?,
+        _ => {}
+    }
+
+    Ok(())
+}
+
 impl AtomType {
     pub fn format(&self, with_type: bool) -> String {
         if with_type {
             match self {
                 &AtomType::Int(num) => format!("Int({})", num),
-                &AtomType::List(ref seq) | &AtomType::Vec(ref seq) => {
+                &AtomType::Str(ref str) => format!("Str({:?})", str),
+                &AtomType::List(ref seq) => {
                     let list = seq.iter()
                         .map(|ref v| v.format(true))
                         .collect::<Vec<_>>()
@@ -59,8 +295,43 @@ impl AtomType {
 
                     format!("List({})", list)
                 }
+                &AtomType::Vec(ref seq) => {
+                    let list = seq.iter()
+                        .map(|ref v| v.format(true))
+                        .collect::<Vec<_>>()
+                        .join(" ");
+
+                    format!("Vec({})", list)
+                }
+                &AtomType::Map(ref map) => {
+                    let entries = map.iter()
+                        .map(|&(ref k, ref v)| format!("{} {}", k.format(true), v.format(true)))
+                        .collect::<Vec<_>>()
+                        .join(" ");
+
+                    format!("Map({})", entries)
+                }
+                &AtomType::Set(ref set) => {
+                    let items = set.iter()
+                        .map(|ref v| v.format(true))
+                        .collect::<Vec<_>>()
+                        .join(" ");
+
+                    format!("Set({})", items)
+                }
+                &AtomType::LazySeq(ref seq) => format!("LazySeq({})", format_lazy(seq, true)),
+                &AtomType::Record(ref record) => {
+                    let entries = record.entries()
+                        .iter()
+                        .map(|&(ref k, ref v)| format!("{} {}", k.format(true), v.format(true)))
+                        .collect::<Vec<_>>()
+                        .join(" ");
+
+                    format!("Record({} {})", record.name(), entries)
+                }
                 &AtomType::Nil => format!("Nil()"),
                 &AtomType::Symbol(ref symbol) => format!("Symbol({})", symbol),
+                &AtomType::Keyword(ref keyword) => format!("Keyword({})", keyword),
                 &AtomType::Func(_) => format!("#func()"),
                 &AtomType::AFunc(ref data) => {
                     let _type = if data.is_macro {
@@ -69,16 +340,25 @@ impl AtomType {
                         "builtin_func"
                     };
 
-                    format!("#{}(exp={} params={})",
-                            _type,
-                            data.exp,
-                            data.params.format(true))
+                    let clauses = data.clauses
+                        .iter()
+                        .map(|clause| format!("exp={} params={}", clause.exp, clause.params.format(true)))
+                        .collect::<Vec<_>>()
+                        .join(" ");
+
+                    format!("#{}({})", _type, clauses)
                 }
+                &AtomType::Partial(..) => format!("#func()"),
+                &AtomType::Multi(ref multi) => format!("Multi({})", multi.name),
+                &AtomType::Reduced(ref value) => format!("Reduced({})", value.format(true)),
+                &AtomType::Ref(ref cell) => format!("Atom({})", cell.deref().format(true)),
+                &AtomType::Var(ref var) => format!("Var({})", var.name),
             }
         } else {
             match self {
                 &AtomType::Int(num) => format!("{}", num),
-                &AtomType::List(ref seq) | &AtomType::Vec(ref seq) => {
+                &AtomType::Str(ref str) => format!("{:?}", str),
+                &AtomType::List(ref seq) => {
                     let list = seq.iter()
                         .map(|ref v| v.format(false))
                         .collect::<Vec<_>>()
@@ -86,8 +366,43 @@ impl AtomType {
 
                     format!("({})", list)
                 }
+                &AtomType::Vec(ref seq) => {
+                    let list = seq.iter()
+                        .map(|ref v| v.format(false))
+                        .collect::<Vec<_>>()
+                        .join(" ");
+
+                    format!("[{}]", list)
+                }
+                &AtomType::Map(ref map) => {
+                    let entries = map.iter()
+                        .map(|&(ref k, ref v)| format!("{} {}", k.format(false), v.format(false)))
+                        .collect::<Vec<_>>()
+                        .join(" ");
+
+                    format!("{{{}}}", entries)
+                }
+                &AtomType::Set(ref set) => {
+                    let items = set.iter()
+                        .map(|ref v| v.format(false))
+                        .collect::<Vec<_>>()
+                        .join(" ");
+
+                    format!("#{{{}}}", items)
+                }
+                &AtomType::LazySeq(ref seq) => format!("({})", format_lazy(seq, false)),
+                &AtomType::Record(ref record) => {
+                    let entries = record.entries()
+                        .iter()
+                        .map(|&(ref k, ref v)| format!("{} {}", k.format(false), v.format(false)))
+                        .collect::<Vec<_>>()
+                        .join(" ");
+
+                    format!("#{}{{{}}}", record.name(), entries)
+                }
                 &AtomType::Nil => format!("nil"),
                 &AtomType::Symbol(ref symbol) => format!("{}", symbol),
+                &AtomType::Keyword(ref keyword) => format!(":{}", keyword),
                 &AtomType::Func(_) => format!("#func()"),
                 &AtomType::AFunc(ref data) => {
                     if data.is_macro {
@@ -96,44 +411,77 @@ impl AtomType {
                         format!("#builtin_func()")
                     }
                 },
+                &AtomType::Partial(..) => format!("#func()"),
+                &AtomType::Multi(ref multi) => format!("#multifn({})", multi.name),
+                &AtomType::Reduced(ref value) => format!("#reduced({})", value),
+                &AtomType::Ref(ref cell) => format!("#atom({})", cell.deref()),
+                &AtomType::Var(ref var) => format!("#'{}", var.name),
             }
         }
     }
 
-
     pub fn apply(&self, args: &[AtomVal]) -> AtomRet {
         match *self {
             AtomType::Func(AtomFunc(f)) => f(args),
+            AtomType::Partial(ref func, ref bound) => {
+                let mut all = bound.clone();
+                all.extend_from_slice(args);
+                func.apply(&all)
+            }
+            AtomType::Multi(ref multi) => multi.call(args),
             AtomType::AFunc(ref fd) => {
-                let fd = fd.clone();
+                let clause = match fd.clause(args.len()) {
+                    Some(clause) => clause,
+                    None => {
+                        let name = fd.name.as_ref().map(|name| name.as_str()).unwrap_or("fn*");
+                        return Err(AtomError::InvalidArgument(format!("wrong number of args ({}) passed to: {}",
+                                                                      args.len(),
+                                                                      name)));
+                    }
+                };
+
                 let func_env = c_env(Some(fd.env.clone()));
-                match *fd.params {
-                    AtomType::List(ref params) => {
-                        env_bind(&func_env, params, &args)?;
-
-                        if let Some(args_count) = params.iter().position(|v| v.is_symbol("&")) {
-                            if let Some(restparams) = params.get(args_count + 1) {
-                                let rest = args.iter().cloned().skip(args_count).collect::<Vec<AtomVal>>();
-
-                                if rest.len() > 0 {
-                                    env_set(&func_env, restparams, c_list(&rest))?;
-                                } else {
-                                    env_set(&func_env, restparams, c_nil())?;
-
-                                }
-                            }
-                        }
-                    },
-                    ref v => return Err(AtomError::InvalidType("list".to_string(), v.format(true)))
+                for (param, arg) in clause.params.get_list()?[..clause.arity].iter().zip(args) {
+                    env_destructure(&func_env, param, arg)?;
+                }
+                if let Some(ref rest) = clause.rest {
+                    if args.len() > clause.arity {
+                        env_destructure(&func_env, rest, &c_list(&args[clause.arity..]))?;
+                    } else {
+                        env_destructure(&func_env, rest, &c_nil())?;
+                    }
                 }
 
                 trace!("action=AtomType#apply env={:?}", func_env);
-                eval(&fd.exp, &func_env)
+                eval_expanded(&clause.exp, &func_env)
             },
             _ => Err(AtomError::InvalidType("function".to_string(), self.format(true)))
         }
     }
 
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            AtomType::Nil => "Nil",
+            AtomType::Int(_) => "Int",
+            AtomType::Str(_) => "Str",
+            AtomType::Symbol(_) => "Symbol",
+            AtomType::Keyword(_) => "Keyword",
+            AtomType::List(_) => "List",
+            AtomType::Vec(_) => "Vec",
+            AtomType::Map(_) => "Map",
+            AtomType::Set(_) => "Set",
+            AtomType::LazySeq(_) => "LazySeq",
+            AtomType::Record(_) => "Record",
+            AtomType::Func(_) |
+            AtomType::Partial(..) => "Func",
+            AtomType::Multi(_) => "MultiFn",
+            AtomType::Reduced(_) => "Reduced",
+            AtomType::AFunc(ref data) => if data.is_macro { "Macro" } else { "AFunc" },
+            AtomType::Ref(_) => "Atom",
+            AtomType::Var(_) => "Var",
+        }
+    }
+
     #[inline]
     pub fn get_int(&self) -> result::Result<i64, AtomError> {
         match *self {
@@ -143,15 +491,45 @@ impl AtomType {
     }
 
     #[inline]
-    pub fn get_list(&self) -> result::Result<&Vec<AtomVal>, AtomError>{
+    pub fn get_str(&self) -> result::Result<&str, AtomError> {
+        match *self {
+            AtomType::Str(ref s) => Ok(s),
+            _ => Err(AtomError::InvalidType("Str".to_string(), self.format(true))),
+        }
+    }
+
+    // Elements of a list, a vector or a lazy sequence, copied unless they are
+    // stored together. A lazy sequence is realized completely.
+    #[inline]
+    pub fn get_list<'a>(&'a self) -> result::Result<Cow<'a, [AtomVal]>, AtomError>{
         trace!("action=AtomType#get_list self={}", self.format(true));
         match *self {
-            AtomType::List(ref list) => Ok(list),
+            AtomType::List(ref list) => Ok(list.as_slice()),
+            AtomType::Vec(ref vec) => Ok(vec.as_slice()),
+            AtomType::LazySeq(ref seq) => Ok(Cow::Owned(seq.to_vec()?)),
             _ => Err(AtomError::InvalidType("List".to_string(), self.format(true))),
         }
 
     }
 
+    #[inline]
+    pub fn get_ref(&self) -> result::Result<&AtomRef, AtomError> {
+        match *self {
+            AtomType::Ref(ref cell) => Ok(cell),
+            _ => Err(AtomError::InvalidType("Atom".to_string(), self.format(true))),
+        }
+    }
+
+    // Watches of an atom or a var
+    #[inline]
+    pub fn get_watches(&self) -> result::Result<&Watches, AtomError> {
+        match *self {
+            AtomType::Ref(ref cell) => Ok(&cell.watches),
+            AtomType::Var(ref var) => Ok(&var.watches),
+            _ => Err(AtomError::InvalidType("Atom".to_string(), self.format(true))),
+        }
+    }
+
     #[inline]
     pub fn get_symbol(&self) -> result::Result<&str, AtomError> {
         match *self {
@@ -167,6 +545,23 @@ impl AtomType {
             _ => false
         }
     }
+
+    #[inline]
+    pub fn is_keyword(&self, keyword: &str) -> bool {
+        match *self {
+            AtomType::Keyword(ref s) => **s == keyword,
+            _ => false
+        }
+    }
+
+    // List starting with the symbol `sym`, like `(quote x)` for "quote"
+    #[inline]
+    pub fn is_form(&self, sym: &str) -> bool {
+        match *self {
+            AtomType::List(ref list) => list.first().map_or(false, |head| head.is_symbol(sym)),
+            _ => false
+        }
+    }
 }
 
 
@@ -179,6 +574,12 @@ pub enum AtomError {
     // message
     InvalidArgument(String),
     UndefinedSymbol(String),
+    // lexer or parser message
+    Syntax(String),
+    // multimethod or protocol method call no method handles
+    NoMethod(String),
+    // file path, error raised while loading it
+    InFile(String, Box<AtomError>),
 }
 
 
@@ -193,6 +594,9 @@ impl Display for AtomError {
             InvalidOperation(ref op) => format!("invalid operation: {}", op),
             InvalidArgument(ref op) => format!("invalid argument: {}", op),
             UndefinedSymbol(ref op) => format!("undefined symbol: {}", op),
+            Syntax(ref msg) => format!("syntax error: {}", msg),
+            NoMethod(ref msg) => msg.clone(),
+            InFile(ref path, ref err) => format!("{}: {}", path, err),
         };
 
         write!(f, "{}", output)
@@ -203,6 +607,7 @@ pub type AtomVal = Rc<AtomType>;
 pub type AtomRet = result::Result<AtomVal, AtomError>;
 
 thread_local!(static NIL: AtomVal = Rc::new(AtomType::Nil));
+thread_local!(static GENSYM_ID: Cell<usize> = Cell::new(0));
 
 pub fn c_nil() -> AtomVal {
     NIL.with(|v| v.clone())
@@ -212,12 +617,179 @@ pub fn c_int(num: i64) -> AtomVal {
     Rc::new(AtomType::Int(num))
 }
 
+pub fn c_string(str: &str) -> AtomVal {
+    Rc::new(AtomType::Str(str.to_string()))
+}
+
 pub fn c_symbol(symbol: &str) -> AtomVal {
     Rc::new(AtomType::Symbol(Rc::new(symbol.to_string())))
 }
 
+pub fn next_gensym_id() -> usize {
+    GENSYM_ID.with(|id| {
+        id.set(id.get() + 1);
+        id.get()
+    })
+}
+
+// Symbol that can't clash with any symbol written by hand.
+pub fn c_gensym(prefix: &str) -> AtomVal {
+    c_symbol(&format!("{}{}", prefix, next_gensym_id()))
+}
+
+pub fn c_keyword(keyword: &str) -> AtomVal {
+    Rc::new(AtomType::Keyword(Rc::new(keyword.to_string())))
+}
+
 pub fn c_list(seq: &[AtomVal]) -> AtomVal {
-    Rc::new(AtomType::List(seq.to_vec()))
+    Rc::new(AtomType::List(AtomList::from_vec(seq.to_vec())))
+}
+
+pub fn c_list_of(list: AtomList) -> AtomVal {
+    Rc::new(AtomType::List(list))
+}
+
+pub fn c_vec(seq: &[AtomVal]) -> AtomVal {
+    Rc::new(AtomType::Vec(AtomVec::from_items(seq)))
+}
+
+pub fn c_vec_of(vec: AtomVec) -> AtomVal {
+    Rc::new(AtomType::Vec(vec))
+}
+
+pub fn c_set(set: AtomSet) -> AtomVal {
+    Rc::new(AtomType::Set(set))
+}
+
+// Sequence produced by calling `func` with `args` once it is needed.
+pub fn c_lazy_seq(func: AtomVal, args: Vec<AtomVal>) -> AtomVal {
+    Rc::new(AtomType::LazySeq(LazySeq::new(func, args)))
+}
+
+// `first` followed by the sequence `rest`, which is left unrealized.
+pub fn c_lazy_cons(first: AtomVal, rest: AtomVal) -> AtomVal {
+    Rc::new(AtomType::LazySeq(LazySeq::cons(first, rest)))
+}
+
+pub fn c_map(map: AtomMap) -> AtomVal {
+    Rc::new(AtomType::Map(map))
+}
+
+pub fn c_record(record: AtomRecord) -> AtomVal {
+    Rc::new(AtomType::Record(record))
+}
+
+pub fn c_ref(value: AtomVal) -> AtomVal {
+    let cell = Rc::new(AtomType::Ref(AtomRef {
+        value: RefCell::new(value),
+        watches: Watches::default(),
+    }));
+    gc::track_atom(&cell);
+    cell
+}
+
+pub fn c_var(name: &str, env: &Env) -> AtomVal {
+    Rc::new(AtomType::Var(AtomVar {
+        name: name.to_string(),
+        env: Rc::downgrade(env),
+        root: RefCell::new(None),
+        watches: Watches::default(),
+    }))
+}
+
+// Sequence protocol: every collection, strings and nil can be walked as a
+// first element and the rest. Maps and records are sequences of [key value]
+// vectors, strings of one character strings.
+
+// List or lazy sequence of the elements of `coll`, nil when it's empty.
+// Lists and lazy sequences are returned as they are.
+pub fn seq(coll: &AtomVal) -> AtomRet {
+    let items = match **coll {
+        AtomType::Nil => return Ok(c_nil()),
+        AtomType::List(ref list) => return Ok(if list.is_empty() { c_nil() } else { coll.clone() }),
+        AtomType::LazySeq(ref lazy) => return Ok(if lazy.step()?.is_some() { coll.clone() } else { c_nil() }),
+        _ => collection_items(coll)?,
+    };
+
+    if items.is_empty() {
+        Ok(c_nil())
+    } else {
+        Ok(c_list_of(AtomList::from_vec(items)))
+    }
+}
+
+// Elements of a collection that isn't a sequence itself.
+fn collection_items(coll: &AtomVal) -> result::Result<Vec<AtomVal>, AtomError> {
+    match **coll {
+        AtomType::Vec(ref vec) => Ok(vec.iter().cloned().collect()),
+        AtomType::Set(ref set) => Ok(set.iter().cloned().collect()),
+        AtomType::Map(ref map) => Ok(map.iter().map(|&(ref k, ref v)| c_vec(&[k.clone(), v.clone()])).collect()),
+        AtomType::Record(ref record) => Ok(record.entries().into_iter().map(|(k, v)| c_vec(&[k, v])).collect()),
+        AtomType::Str(ref str) => Ok(str.chars().map(|c| c_string(&c.to_string())).collect()),
+        _ => Err(AtomError::InvalidType("Seq".to_string(), coll.format(true))),
+    }
+}
+
+// First element and the rest of `coll`, None when it's empty. The rest of a
+// vector steps through it by index, maps, sets and strings are copied into a
+// vector once, so walking any collection takes linear time.
+pub fn uncons(coll: &AtomVal) -> result::Result<Option<(AtomVal, AtomVal)>, AtomError> {
+    match **coll {
+        AtomType::Nil => Ok(None),
+        AtomType::List(ref list) => Ok(list.first().map(|first| (first.clone(), c_list_of(list.rest())))),
+        AtomType::LazySeq(ref lazy) => lazy.step(),
+        AtomType::Vec(ref vec) => Ok(vec.get(0).map(|first| (first.clone(), indexed_rest(coll.clone(), 1)))),
+        _ => uncons(&c_vec(&collection_items(coll)?)),
+    }
+}
+
+// Elements of the vector `items` from `index` on, realized one at a time.
+fn indexed_rest(items: AtomVal, index: usize) -> AtomVal {
+    c_lazy_seq(c_func(indexed_step), vec![items, c_int(index as i64)])
+}
+
+// [items index]
+fn indexed_step(args: &[AtomVal]) -> AtomRet {
+    let index = args[1].get_int()? as usize;
+    match *args[0] {
+        AtomType::Vec(ref vec) => {
+            Ok(vec.get(index)
+                .map(|item| c_lazy_cons(item.clone(), indexed_rest(args[0].clone(), index + 1)))
+                .unwrap_or_else(c_nil))
+        }
+        _ => Err(AtomError::InvalidType("Vec".to_string(), args[0].format(true))),
+    }
+}
+
+// Elements of `coll`, realizing a lazy sequence as they are taken.
+pub fn seq_iter(coll: &AtomVal) -> SeqIter {
+    SeqIter { rest: coll.clone() }
+}
+
+pub fn seq_items(coll: &AtomVal) -> result::Result<Vec<AtomVal>, AtomError> {
+    seq_iter(coll).collect()
+}
+
+pub struct SeqIter {
+    rest: AtomVal,
+}
+
+impl Iterator for SeqIter {
+    type Item = result::Result<AtomVal, AtomError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match uncons(&self.rest) {
+            Ok(Some((first, rest))) => {
+                self.rest = rest;
+                Some(Ok(first))
+            }
+            Ok(None) => None,
+            Err(err) => {
+                self.rest = c_nil();
+                Some(Err(err))
+            }
+        }
+    }
 }
 
 pub fn c_func(f: fn(&[AtomVal]) -> AtomRet) -> AtomVal {
@@ -225,8 +797,35 @@ pub fn c_func(f: fn(&[AtomVal]) -> AtomRet) -> AtomVal {
 }
 
 
-pub fn c_afunc(env: Env, params: AtomVal, exp: AtomVal) -> AtomVal {
-    Rc::new(AtomType::AFunc(AFuncData { exp, env, params, is_macro: false }))
+// `func` called with `args` before the args it's given.
+pub fn c_partial(func: AtomVal, args: Vec<AtomVal>) -> AtomVal {
+    Rc::new(AtomType::Partial(func, args))
+}
+
+pub fn c_multi(multi: AtomMulti) -> AtomVal {
+    Rc::new(AtomType::Multi(multi))
+}
+
+pub fn c_reduced(value: AtomVal) -> AtomVal {
+    Rc::new(AtomType::Reduced(value))
+}
+
+pub fn c_afunc(env: Env, clauses: Vec<FnClause>) -> AtomVal {
+    Rc::new(AtomType::AFunc(AFuncData {
+        clauses: Rc::new(clauses),
+        env,
+        name: None,
+        meta: AtomMap::new(),
+        is_macro: false,
+    }))
+}
+
+pub fn c_named(fd: &AFuncData, name: &str, meta: AtomMap) -> AtomVal {
+    let mut fd = fd.clone();
+    fd.name = Some(Rc::new(name.to_string()));
+    fd.meta = meta;
+
+    Rc::new(AtomType::AFunc(fd))
 }
 
 pub fn c_macro(fd: &AFuncData) -> AtomVal {
@@ -242,7 +841,14 @@ mod tests {
     use super::c_nil;
     use super::c_int;
     use super::c_symbol;
+    use super::c_string;
     use super::c_list;
+    use super::c_vec;
+    use super::c_keyword;
+    use super::c_map;
+    use super::AtomMap;
+    use super::AtomType;
+    use super::{seq, seq_items, uncons};
 
     #[test]
     fn test_nil() {
@@ -259,6 +865,11 @@ mod tests {
         assert_eq!(format!("{}", c_symbol("test")), "test");
     }
 
+    #[test]
+    fn test_string() {
+        assert_eq!(format!("{}", c_string("a \"b\"")), "\"a \\\"b\\\"\"");
+    }
+
     #[test]
     fn test_list() {
         let foo = c_int(0);
@@ -268,6 +879,48 @@ mod tests {
         assert_eq!(format!("{}", list), "(0 1)");
     }
 
+    #[test]
+    fn test_vec_and_keyword() {
+        let vec = c_vec(&[c_keyword("as"), c_list(&[c_int(1)])]);
+
+        assert_eq!(format!("{}", vec), "[:as (1)]");
+    }
+
+    #[test]
+    fn test_map_equality_ignores_order() {
+        let a = AtomMap::from_flat(&[c_keyword("a"), c_int(1), c_keyword("b"), c_int(2)]).unwrap();
+        let b = AtomMap::from_flat(&[c_keyword("b"), c_int(2), c_keyword("a"), c_int(1)]).unwrap();
+
+        assert_eq!(format!("{}", c_map(a.clone())), "{:a 1 :b 2}");
+        assert_eq!(c_map(a), c_map(b));
+    }
+
+    #[test]
+    fn test_rest_of_vector_shares_it() {
+        let items = (0..5).map(c_int).collect::<Vec<_>>();
+        let (first, rest) = uncons(&c_vec(&items)).unwrap().unwrap();
+
+        assert_eq!(first, c_int(0));
+        assert!(match *rest {
+            AtomType::LazySeq(ref lazy) => !lazy.is_realized(),
+            _ => false,
+        });
+        assert_eq!(seq_items(&rest).unwrap(), &items[1..]);
+    }
+
+    #[test]
+    fn test_seq_of_each_collection() {
+        let map = c_map(AtomMap::from_flat(&[c_keyword("a"), c_int(1)]).unwrap());
+
+        assert_eq!(seq(&c_nil()), Ok(c_nil()));
+        assert_eq!(seq(&c_vec(&[])), Ok(c_nil()));
+        assert_eq!(seq(&c_vec(&[c_int(1)])), Ok(c_list(&[c_int(1)])));
+        assert_eq!(seq_items(&map), Ok(vec![c_vec(&[c_keyword("a"), c_int(1)])]));
+        assert_eq!(seq_items(&c_string("ab")), Ok(vec![c_string("a"), c_string("b")]));
+        assert_eq!(uncons(&c_list(&[c_int(1)])), Ok(Some((c_int(1), c_list(&[])))));
+        assert!(seq(&c_int(1)).is_err());
+    }
+
     #[test]
     fn test_nested_seq() {
         let foo = c_int(0);
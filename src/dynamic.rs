@@ -0,0 +1,81 @@
+use std::cell::RefCell;
+use fnv::FnvHashMap;
+use data::AtomVal;
+
+// Values of dynamic vars, by qualified var name.
+pub type Frame = FnvHashMap<String, AtomVal>;
+
+// Frames pushed by `binding`, the newest last. Kept apart from the lexical
+// envs, since a binding is seen by every function called during its extent.
+thread_local!(static BINDINGS: RefCell<Vec<Frame>> = RefCell::new(vec![]));
+
+// Pops the frame pushed by `with_bindings`, also when unwinding.
+struct FrameGuard;
+
+impl Drop for FrameGuard {
+    fn drop(&mut self) {
+        BINDINGS.with(|bindings| bindings.borrow_mut().pop());
+    }
+}
+
+// Runs `f` with the values of `frame` bound, whatever it returns.
+pub fn with_bindings<T, F>(frame: Frame, f: F) -> T
+    where F: FnOnce() -> T
+{
+    BINDINGS.with(|bindings| bindings.borrow_mut().push(frame));
+    let _guard = FrameGuard;
+
+    f()
+}
+
+pub fn lookup(name: &str) -> Option<AtomVal> {
+    BINDINGS.with(|bindings| bindings.borrow().iter().rev().filter_map(|frame| frame.get(name)).next().cloned())
+}
+
+// Changes the innermost binding of `name`, false when it isn't bound.
+pub fn set(name: &str, value: AtomVal) -> bool {
+    BINDINGS.with(|bindings| {
+        for frame in bindings.borrow_mut().iter_mut().rev() {
+            if let Some(slot) = frame.get_mut(name) {
+                *slot = value;
+                return true;
+            }
+        }
+
+        false
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{with_bindings, lookup, set, Frame};
+    use data::{AtomError, c_int};
+
+    fn frame(name: &str, value: i64) -> Frame {
+        let mut frame = Frame::default();
+        frame.insert(name.to_string(), c_int(value));
+        frame
+    }
+
+    #[test]
+    fn nested_bindings() {
+        with_bindings(frame("x", 1), || {
+            with_bindings(frame("x", 2), || assert_eq!(lookup("x"), Some(c_int(2))));
+            assert!(set("x", c_int(3)));
+            assert_eq!(lookup("x"), Some(c_int(3)));
+        });
+
+        assert_eq!(lookup("x"), None);
+        assert!(!set("x", c_int(4)));
+    }
+
+    #[test]
+    fn bindings_pop_on_error() {
+        let result: Result<(), AtomError> = with_bindings(frame("y", 1), || {
+            Err(AtomError::InvalidOperation("boom".to_string()))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(lookup("y"), None);
+    }
+}
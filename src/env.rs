@@ -1,16 +1,23 @@
-use super::data::{AtomVal, AtomType, c_nil, AtomError};
-use std::rc::Rc;
+use super::data::{AtomVal, AtomType, AtomMap, c_nil, c_list, c_keyword, seq_items, AtomError};
+use namespace::Namespace;
+use gc;
+use std::rc::{Rc, Weak};
 use std::cell::RefCell;
 use std::fmt;
+use std::borrow::Cow;
+use std::mem;
 use fnv::FnvHashMap;
 
 #[derive(PartialEq)]
 pub struct EnvType {
     parent: Option<Env>,
     data: FnvHashMap<Rc<String>, AtomVal>,
+    // set on the top env of each namespace
+    ns: Option<Rc<Namespace>>,
 }
 
 pub type Env = Rc<RefCell<EnvType>>;
+pub type WeakEnv = Weak<RefCell<EnvType>>;
 
 
 impl fmt::Display for EnvType {
@@ -42,11 +49,54 @@ impl fmt::Debug for EnvType {
 }
 
 
-pub fn c_env(env: Option<Env>) -> Env {
-    Rc::new(RefCell::new(EnvType {
-        parent: env,
+pub fn c_env(parent: Option<Env>) -> Env {
+    let env = Rc::new(RefCell::new(EnvType {
+        parent: parent,
         data: FnvHashMap::default(),
-    }))
+        ns: None,
+    }));
+    gc::track_env(&env);
+    env
+}
+
+pub fn c_ns_env(parent: Option<Env>, ns: Rc<Namespace>) -> Env {
+    let env = Rc::new(RefCell::new(EnvType {
+        parent: parent,
+        data: FnvHashMap::default(),
+        ns: Some(ns),
+    }));
+    gc::track_env(&env);
+    env
+}
+
+pub fn env_is_namespace(env: &Env) -> bool {
+    env.borrow().ns.is_some()
+}
+
+// Namespace of the nearest enclosing namespace env.
+pub fn env_namespace(env: &Env) -> Option<Rc<Namespace>> {
+    let env_borrow = env.borrow();
+    match env_borrow.ns {
+        Some(ref ns) => Some(ns.clone()),
+        None => env_borrow.parent.as_ref().and_then(env_namespace),
+    }
+}
+
+// Nearest enclosing namespace env, or the root env outside of namespaces.
+pub fn env_namespace_env(env: &Env) -> Env {
+    let env_borrow = env.borrow();
+    match (&env_borrow.ns, &env_borrow.parent) {
+        (&None, &Some(ref parent)) => env_namespace_env(parent),
+        _ => env.clone(),
+    }
+}
+
+// Value bound to `key` in `env` itself, ignoring its parents.
+pub fn env_get_own(env: &Env, key: &AtomVal) -> Option<AtomVal> {
+    match **key {
+        AtomType::Symbol(ref str) => env.borrow().data.get(str).cloned(),
+        _ => None,
+    }
 }
 
 fn env_find_inner(env: &Env, key: &Rc<String>) -> Option<(Env, AtomVal)> {
@@ -81,6 +131,60 @@ pub fn env_set(env: &Env, key: &AtomVal, value: AtomVal) -> Result<(), AtomError
 
 }
 
+// Replaces the value of `key` in the nearest env binding it, looking no
+// further than the enclosing namespace env: bindings of other namespaces,
+// like the core one, can't be changed from here.
+pub fn env_assign(env: &Env, key: &AtomVal, value: AtomVal) -> Result<(), AtomError> {
+    let mut current = env.clone();
+    loop {
+        if env_get_own(&current, key).is_some() {
+            return env_set(&current, key, value);
+        }
+        let parent = {
+            let env_borrow = current.borrow();
+            match env_borrow.ns {
+                Some(_) => None,
+                None => env_borrow.parent.clone(),
+            }
+        };
+        match parent {
+            Some(parent) => current = parent,
+            None => break,
+        }
+    }
+
+    let owner = match **key {
+        AtomType::Symbol(ref name) => env_defining_namespace(env, name),
+        _ => None,
+    };
+    match owner {
+        Some(owner) => {
+            let here = env_namespace(env).map(|ns| ns.name.clone()).unwrap_or_default();
+            Err(AtomError::InvalidOperation(format!("can't set! {}/{} from namespace {}",
+                                                    owner.name,
+                                                    key,
+                                                    here)))
+        }
+        None => Err(AtomError::UndefinedSymbol(format!("{}", key))),
+    }
+}
+
+// Namespace of the namespace env `key` is defined in, ignoring local envs.
+pub fn env_defining_namespace(env: &Env, key: &Rc<String>) -> Option<Rc<Namespace>> {
+    let env_borrow = env.borrow();
+    match env_borrow.ns {
+        Some(ref ns) if env_borrow.data.contains_key(key) => Some(ns.clone()),
+        _ => env_borrow.parent.as_ref().and_then(|parent| env_defining_namespace(parent, key)),
+    }
+}
+
+pub fn env_root(env: &Env) -> Env {
+    match env.borrow().parent {
+        Some(ref parent) => env_root(parent),
+        None => env.clone(),
+    }
+}
+
 pub fn env_get(env: &Env, key: &AtomVal) -> Option<AtomVal> {
     match env_find(env, key) {
         None => None,
@@ -88,6 +192,41 @@ pub fn env_get(env: &Env, key: &AtomVal) -> Option<AtomVal> {
     }
 }
 
+// All bindings visible from `env`, inner bindings shadowing outer ones,
+// sorted by name.
+pub fn env_bindings(env: &Env) -> Vec<(Rc<String>, AtomVal)> {
+    let mut bindings: FnvHashMap<Rc<String>, AtomVal> = FnvHashMap::default();
+    let mut current = Some(env.clone());
+
+    while let Some(env) = current {
+        let env_borrow = env.borrow();
+        for (key, value) in env_borrow.data.iter() {
+            bindings.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+        current = env_borrow.parent.clone();
+    }
+
+    let mut bindings = bindings.into_iter().collect::<Vec<_>>();
+    bindings.sort_by(|a, b| a.0.cmp(&b.0));
+    bindings
+}
+
+// Parent and values `env` holds on to, None while it's being changed.
+pub fn env_references(env: &Env) -> Option<(Option<Env>, Vec<AtomVal>)> {
+    let env_borrow = env.try_borrow().ok()?;
+    Some((env_borrow.parent.clone(), env_borrow.data.values().cloned().collect()))
+}
+
+// Drops everything `env` holds on to, breaking the cycles it's part of.
+pub fn env_clear(env: &Env) {
+    let (parent, data) = {
+        let mut env_borrow = env.borrow_mut();
+        (env_borrow.parent.take(), mem::replace(&mut env_borrow.data, FnvHashMap::default()))
+    };
+    drop(parent);
+    drop(data);
+}
+
 pub fn env_bind(env: &Env, params: &[AtomVal], args: &[AtomVal]) -> Result<(), AtomError> {
     for (index, param) in params.iter().enumerate() {
         env_set(env, param, args.get(index).cloned().unwrap_or_else(c_nil))?;
@@ -95,11 +234,86 @@ pub fn env_bind(env: &Env, params: &[AtomVal], args: &[AtomVal]) -> Result<(), A
     Ok(())
 }
 
+// Value taken apart by a map pattern, keyword arguments like `(:a 1 :b 2)`
+// being read as a map.
+fn as_map(value: &AtomVal) -> Result<AtomMap, AtomError> {
+    match **value {
+        AtomType::Map(ref map) => Ok(map.clone()),
+        AtomType::Record(ref record) => Ok(record.values().clone()),
+        AtomType::Nil => Ok(AtomMap::new()),
+        AtomType::List(_) |
+        AtomType::Vec(_) => {
+            AtomMap::from_flat(&value.get_list()?).ok_or_else(|| {
+                AtomError::InvalidArgument(format!("no value for key in {}", value))
+            })
+        }
+        _ => Err(AtomError::InvalidType("Map".to_string(), value.format(true))),
+    }
+}
+
+// Binds the symbols of `pattern` to the matching parts of `value`. A list or
+// vector pattern takes elements by position, `& rest` the remaining ones and
+// `:as name` the whole value. A map pattern takes values by key,
+// `{:keys [a b]}` being short for `{a :a b :b}`.
+pub fn env_destructure(env: &Env, pattern: &AtomVal, value: &AtomVal) -> Result<(), AtomError> {
+    match **pattern {
+        AtomType::Symbol(_) => env_set(env, pattern, value.clone()),
+        AtomType::List(_) |
+        AtomType::Vec(_) => {
+            let patterns = pattern.get_list()?;
+            let items = match **value {
+                AtomType::List(_) |
+                AtomType::Vec(_) => value.get_list()?,
+                _ => Cow::Owned(seq_items(value)?),
+            };
+
+            let mut index = 0;
+            let mut patterns = patterns.iter();
+            while let Some(pattern) = patterns.next() {
+                if pattern.is_symbol("&") || pattern.is_keyword("as") {
+                    let target = patterns.next().ok_or_else(|| {
+                        AtomError::InvalidArgument(format!("missing binding after {}", pattern))
+                    })?;
+
+                    if pattern.is_keyword("as") {
+                        env_destructure(env, target, value)?;
+                    } else if index < items.len() {
+                        env_destructure(env, target, &c_list(&items[index..]))?;
+                    } else {
+                        env_destructure(env, target, &c_nil())?;
+                    }
+                } else {
+                    env_destructure(env, pattern, &items.get(index).cloned().unwrap_or_else(c_nil))?;
+                    index += 1;
+                }
+            }
+            Ok(())
+        }
+        AtomType::Map(ref patterns) => {
+            let map = as_map(value)?;
+            let map_value = |key: &AtomVal| map.get(key).cloned().unwrap_or_else(c_nil);
+            for &(ref pattern, ref key) in patterns.iter() {
+                if pattern.is_keyword("keys") {
+                    for name in key.get_list()?.iter() {
+                        env_set(env, name, map_value(&c_keyword(name.get_symbol()?)))?;
+                    }
+                } else if pattern.is_keyword("as") {
+                    env_destructure(env, key, value)?;
+                } else {
+                    env_destructure(env, pattern, &map_value(key))?;
+                }
+            }
+            Ok(())
+        }
+        _ => Err(AtomError::InvalidArgument(format!("can't bind to {}", pattern))),
+    }
+}
+
 #[allow(unused_must_use)]
 #[cfg(test)]
 mod tests {
-    use super::{c_env, env_set, env_get};
-    use data::{c_symbol, c_int};
+    use super::{c_env, env_set, env_get, env_bindings, env_destructure};
+    use data::{c_symbol, c_int, c_keyword, c_list, c_vec, c_map, AtomMap};
 
     #[test]
     fn test_c_env() {
@@ -140,4 +354,38 @@ mod tests {
 
         assert!(env_get(&env, &c_symbol("Missing")).is_none());
     }
+
+    #[test]
+    fn test_bindings_shadowing() {
+        let env = c_env(None);
+        env_set(&env, &c_symbol("a"), c_int(1));
+        env_set(&env, &c_symbol("b"), c_int(2));
+
+        let child = c_env(Some(env));
+        env_set(&child, &c_symbol("a"), c_int(3));
+
+        let bindings = env_bindings(&child)
+            .iter()
+            .map(|&(ref key, ref value)| format!("{} {}", key, value))
+            .collect::<Vec<_>>();
+
+        assert_eq!(bindings, vec!["a 3", "b 2"]);
+    }
+
+    #[test]
+    fn test_destructure() {
+        let env = c_env(None);
+        let mut keys = AtomMap::new();
+        keys.insert(c_keyword("keys"), c_vec(&[c_symbol("a"), c_symbol("b")]));
+        let mut map = AtomMap::new();
+        map.insert(c_keyword("a"), c_int(1));
+
+        let pattern = c_vec(&[c_symbol("x"),
+                              c_vec(&[c_symbol("y"), c_symbol("&"), c_symbol("ys")]),
+                              c_map(keys)]);
+        let value = c_list(&[c_int(1), c_list(&[c_int(2), c_int(3)]), c_map(map)]);
+        env_destructure(&env, &pattern, &value).unwrap();
+
+        assert_eq!(format!("{}", *env.borrow()), "{a 1 b nil x 1 y 2 ys (3)}");
+    }
 }
@@ -1,82 +1,1020 @@
-use data::{AtomVal, AtomType, AtomRet, AtomError, c_nil, c_list, c_afunc, c_symbol, c_macro};
-use env::{env_set, env_get, env_bind, Env};
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use fnv::FnvHashMap;
+use data::{AtomVal, AtomType, AtomRet, AtomError, AtomMap, FnClause, c_nil, c_int, c_list, c_vec,
+           c_map, c_set, c_afunc, c_lazy_seq, c_record, c_partial, c_multi, c_func, c_symbol, c_keyword,
+           c_macro, c_named, next_gensym_id};
+use env::{c_env, env_set, env_assign, env_get, env_bind, env_destructure, env_namespace, env_defining_namespace,
+          Env};
+use map::AtomSet;
+use record::{self, AtomRecord, RecordType};
+use multi::AtomMulti;
 use lexer::lex;
 use parser::Parser;
+use load::{load_file, require};
+use namespace;
+use dynamic;
+use gc;
+
+// Forms handled directly by `eval_exp` instead of being looked up in Env.
+pub const SPECIAL_FORMS: &'static [&'static str] = &["quote", "def", "if", "fn*", "loop", "recur",
+                                                     "defmacro", "eval", "do", "macroexpand", "load",
+                                                     "require", "ns", "in-ns", "def-", "backquote",
+                                                     "macroexpand-1", "macroexpand-all", "let",
+                                                     "let*", "fn", "defn", "defn-", "when", "when-not",
+                                                     "cond", "and", "or", "if-let", "when-let",
+                                                     "case", "case*", "match", "set!", "var",
+                                                     "binding", "with-meta", "lazy-seq",
+                                                     "defrecord", "defmulti", "defmethod", "defprotocol",
+                                                     "extend-type"];
 
 fn safe_get(args: &[AtomVal], index: usize) -> AtomVal {
     args.get(index).cloned().unwrap_or_else(c_nil)
 }
 
+// Elements of `args` from `index` on, empty when there are none.
+fn rest_from(args: &[AtomVal], index: usize) -> &[AtomVal] {
+    if index < args.len() { &args[index..] } else { &[] }
+}
+
 fn op_quote(args: &[AtomVal]) -> AtomRet {
     Ok(safe_get(args, 1))
 }
 
+// Name and meta of a `^meta name` form, `^:key` standing for `{:key 1}`.
+fn name_and_meta(name_atom: &AtomVal) -> Result<(AtomVal, AtomMap), AtomError> {
+    let mut meta = AtomMap::new();
+    if !name_atom.is_form("with-meta") {
+        return Ok((name_atom.clone(), meta));
+    }
+    let items = &name_atom.get_list()?;
+
+    match *safe_get(items, 2) {
+        AtomType::Map(ref attrs) => {
+            for &(ref key, ref value) in attrs.iter() {
+                meta.insert(key.clone(), value.clone());
+            }
+        }
+        AtomType::Keyword(_) => meta.insert(safe_get(items, 2), c_int(1)),
+        ref other => return Err(AtomError::InvalidType("Map".to_string(), other.format(true))),
+    }
+
+    Ok((safe_get(items, 1), meta))
+}
+
+// Binds `value` to `name`, naming it and attaching `meta` when it is a
+// function without a name yet. `:dynamic` in meta makes a dynamic var.
+fn define(name_atom: &AtomVal, value: AtomVal, meta: AtomMap, env: &Env) -> AtomRet {
+    let name = name_atom.get_symbol()?;
+    let dynamic = meta.get(&c_keyword("dynamic")).map_or(false, is_truthy);
+    let value = match *value {
+        AtomType::AFunc(ref fd) if fd.name.is_none() => c_named(fd, name, meta),
+        _ => value.clone(),
+    };
+
+    namespace::intern(env, name_atom, value, dynamic)
+}
+
 fn op_def(args: &[AtomVal], env: &Env) -> AtomRet {
     trace!("action=op_def args={:?}", args);
+    let (name_atom, meta) = name_and_meta(&safe_get(args, 1))?;
+    let value = eval_expanded(&safe_get(args, 2), env)?;
+    define(&name_atom, value, meta, env)
+}
+
+// [set! name value] - changes the binding `name` already has
+fn op_set(args: &[AtomVal], env: &Env) -> AtomRet {
     let name_atom = safe_get(args, 1);
+    name_atom.get_symbol()?;
+
+    let value = eval_expanded(&safe_get(args, 2), env)?;
+    if let Some(current) = env_get(env, &name_atom) {
+        if let AtomType::Var(ref var) = *current {
+            if var.is_dynamic() {
+                if !dynamic::set(&var.name, value.clone()) {
+                    return Err(AtomError::InvalidOperation(format!("can't set! {} outside of binding",
+                                                                   var.name)));
+                }
+                return Ok(value);
+            }
+        }
+    }
+
+    env_assign(env, &name_atom, value.clone())?;
+    Ok(value)
+}
+
+// Value bound to a symbol, dynamic vars giving their current value.
+fn symbol_value(value: AtomVal) -> AtomVal {
+    match *value {
+        AtomType::Var(ref var) if var.is_dynamic() => var.deref(),
+        _ => value.clone(),
+    }
+}
+
+// Qualified name of the dynamic var `name_atom` refers to.
+fn dynamic_var_name(name_atom: &AtomVal, env: &Env) -> Result<String, AtomError> {
     let name = name_atom.get_symbol()?;
-    let value = eval(&safe_get(args, 2), env)?;
+    let value = match env_get(env, name_atom) {
+        Some(value) => value,
+        None => namespace::resolve(env, name)?,
+    };
 
-    let _ = env_set(&env, &name_atom, value);
-    Ok(c_symbol(name))
+    match *value {
+        AtomType::Var(ref var) if var.is_dynamic() => Ok(var.name.clone()),
+        _ => Err(AtomError::InvalidOperation(format!("can't dynamically bind non-dynamic var: {}", name))),
+    }
+}
+
+// [binding [var value...] body...] - rebinds dynamic vars while body runs
+fn op_binding(args: &[AtomVal], env: &Env) -> AtomRet {
+    let mut frame = dynamic::Frame::default();
+    for (name_atom, value) in let_bindings(&safe_get(args, 1))? {
+        let name = dynamic_var_name(&name_atom, env)?;
+        frame.insert(name, eval_expanded(&value, env)?);
+    }
+
+    dynamic::with_bindings(frame, || eval_body(rest_from(args, 2), env))
 }
 
+// [def- name value] - def visible only inside its namespace
+fn op_def_private(args: &[AtomVal], env: &Env) -> AtomRet {
+    let var = op_def(args, env)?;
+    namespace::mark_private(env, name_and_meta(&safe_get(args, 1))?.0.get_symbol()?)?;
+    Ok(var)
+}
+
+// [var name] - var created by the def of `name`
+fn op_var(args: &[AtomVal], env: &Env) -> AtomRet {
+    let name_atom = safe_get(args, 1);
+    match *name_atom {
+        AtomType::Symbol(ref name) => {
+            namespace::find_var(env, name).ok_or_else(|| AtomError::UndefinedSymbol(name.to_string()))
+        }
+        _ => Err(AtomError::InvalidType("Symbol".to_string(), name_atom.format(true))),
+    }
+}
+
+// [ns name (:require lib [lib :as alias]...)]
+fn op_ns(args: &[AtomVal], env: &Env) -> AtomRet {
+    let name_atom = safe_get(args, 1);
+    let ns_env = namespace::switch(env, name_atom.get_symbol()?)?;
+
+    for clause in args.iter().skip(2) {
+        let clause = &clause.get_list()?;
+        if !safe_get(clause, 0).is_keyword("require") {
+            return Err(AtomError::InvalidArgument(format!("unsupported ns clause: {}",
+                                                          safe_get(clause, 0))));
+        }
+
+        for spec in clause.iter().skip(1) {
+            require(spec, &ns_env)?;
+        }
+    }
+
+    Ok(name_atom)
+}
+
+fn op_in_ns(args: &[AtomVal], env: &Env) -> AtomRet {
+    let name_atom = eval_expanded(&safe_get(args, 1), env)?;
+    namespace::switch(env, name_atom.get_symbol()?)?;
+    Ok(name_atom)
+}
+
+// Whether the forms following `fn*` are arity clauses `([params] body...)`
+// rather than a single `(params) body...`.
+fn is_multi_arity(forms: &[AtomVal]) -> bool {
+    !forms.is_empty() &&
+    forms.iter().all(|form| match **form {
+        AtomType::List(ref clause) => {
+            clause.first().map_or(false, |params| match **params {
+                AtomType::Vec(_) => true,
+                _ => false,
+            })
+        }
+        _ => false,
+    })
+}
+
+fn fn_clause(params: &AtomVal, body: &[AtomVal]) -> Result<FnClause, AtomError> {
+    if let Some(index) = params.get_list()?.iter().position(|v| v.is_symbol("&")) {
+        if params.get_list()?.len() != index + 2 {
+            return Err(AtomError::InvalidArgument("& must be followed by a single param"
+                .to_string()));
+        }
+    }
+
+    // several body forms are evaluated as a `do`
+    let exp = match body.len() {
+        1 => body[0].clone(),
+        _ => {
+            let mut exp = vec![c_symbol("do")];
+            exp.extend_from_slice(body);
+            c_list(&exp)
+        }
+    };
+
+    Ok(FnClause::new(params.clone(), exp))
+}
+
+fn fn_clauses(forms: &[AtomVal]) -> Result<Vec<FnClause>, AtomError> {
+    if !is_multi_arity(forms) {
+        let body = if forms.is_empty() { forms } else { &forms[1..] };
+        return Ok(vec![fn_clause(&safe_get(forms, 0), body)?]);
+    }
+
+    let mut clauses = Vec::with_capacity(forms.len());
+    for clause in forms {
+        let clause = &clause.get_list()?;
+        clauses.push(fn_clause(&clause[0], &clause[1..])?);
+    }
+
+    Ok(clauses)
+}
+
+// [fn* (params) body...] or [fn* ([params] body...)...]
 fn op_lambda(args: &[AtomVal], env: &Env) -> AtomRet {
-    Ok(c_afunc(env.clone(), safe_get(args, 1), safe_get(args, 2)))
+    Ok(c_afunc(env.clone(), fn_clauses(&args[1..])?))
+}
+
+// [defrecord Name [fields...]] - defines ->Name taking the fields in order,
+// map->Name taking a map, the predicate Name? and an accessor Name-field for
+// each field
+fn op_defrecord(args: &[AtomVal], env: &Env) -> AtomRet {
+    let name_atom = safe_get(args, 1);
+    let name = name_atom.get_symbol()?;
+    let mut names = vec![];
+    for field in safe_get(args, 2).get_list()?.iter() {
+        names.push(field.get_symbol()?.to_string());
+    }
+    let fields = names.iter().map(|field| c_keyword(field)).collect::<Vec<_>>();
+
+    let proto = c_record(AtomRecord::empty(RecordType {
+        name: name.to_string(),
+        fields: fields.clone(),
+    }));
+    let bound = |func: fn(&[AtomVal]) -> AtomRet, args: &[AtomVal]| {
+        let mut bound = vec![proto.clone()];
+        bound.extend_from_slice(args);
+        c_partial(c_func(func), bound)
+    };
+
+    let mut definitions = vec![(format!("->{}", name), bound(record::positional, &[])),
+                               (format!("map->{}", name), bound(record::from_map, &[])),
+                               (format!("{}?", name), bound(record::is_instance, &[]))];
+    for (field, keyword) in names.iter().zip(fields) {
+        definitions.push((format!("{}-{}", name, field), bound(record::field, &[keyword])));
+    }
+    for (definition, func) in definitions {
+        define(&c_symbol(&definition), func, AtomMap::new(), env)?;
+    }
+
+    Ok(name_atom)
+}
+
+// [defmulti name doc? {attrs}? dispatch-fn]
+fn op_defmulti(args: &[AtomVal], env: &Env) -> AtomRet {
+    let (name_atom, meta, index) = definition_parts(args)?;
+    let dispatch = eval_expanded(&safe_get(args, index), env)?;
+    let multi = AtomMulti::new(name_atom.get_symbol()?, dispatch);
+    define(&name_atom, c_multi(multi), meta, env)
+}
+
+// Method added to the multimethod or protocol method `name`, named after it.
+fn add_method(name: &AtomVal, value: AtomVal, forms: &[AtomVal], env: &Env) -> AtomRet {
+    let multi_atom = eval_expanded(name, env)?;
+    let multi = match *multi_atom {
+        AtomType::Multi(ref multi) => multi,
+        ref other => return Err(AtomError::InvalidType("MultiFn".to_string(), other.format(true))),
+    };
+
+    let method = match *c_afunc(env.clone(), fn_clauses(forms)?) {
+        AtomType::AFunc(ref fd) => c_named(fd, &multi.name, AtomMap::new()),
+        _ => unreachable!(),
+    };
+    multi.add_method(value, method);
+    Ok(multi_atom.clone())
+}
+
+// [defmethod name dispatch-value (params) body...] - :default is called when
+// no other method matches
+fn op_defmethod(args: &[AtomVal], env: &Env) -> AtomRet {
+    let value = eval_expanded(&safe_get(args, 2), env)?;
+    add_method(&safe_get(args, 1), value, rest_from(args, 3), env)
+}
+
+// [defprotocol Name doc? (method [params]... doc?)...] - defines each method,
+// dispatching on the type of its first arg
+fn op_defprotocol(args: &[AtomVal], env: &Env) -> AtomRet {
+    let name_atom = safe_get(args, 1);
+    let name = name_atom.get_symbol()?;
+
+    let mut methods = vec![];
+    for signature in rest_from(args, 2) {
+        if let AtomType::Str(_) = **signature {
+            continue;
+        }
+        let method = safe_get(&signature.get_list()?, 0);
+        define(&method, c_multi(AtomMulti::protocol_method(method.get_symbol()?, name)), AtomMap::new(), env)?;
+        methods.push(method);
+    }
+
+    let mut protocol = AtomMap::new();
+    protocol.insert(c_keyword("name"), name_atom.clone());
+    protocol.insert(c_keyword("methods"), c_vec(&methods));
+    define(&name_atom, c_map(protocol), AtomMap::new(), env)?;
+    Ok(name_atom)
+}
+
+// [extend-type Type Protocol (method [params] body...)... Protocol2 ...] -
+// Type is a record name, nil, or a kind of value like Int, Str or Vec
+fn op_extend_type(args: &[AtomVal], env: &Env) -> AtomRet {
+    let type_atom = match *safe_get(args, 1) {
+        AtomType::Nil => c_symbol("Nil"),
+        AtomType::Symbol(_) => safe_get(args, 1),
+        ref other => return Err(AtomError::InvalidType("Symbol".to_string(), other.format(true))),
+    };
+
+    let mut protocol: Option<AtomVal> = None;
+    for form in rest_from(args, 2) {
+        if let AtomType::Symbol(ref name) = **form {
+            protocol = Some(c_symbol(name.rsplit('/').next().unwrap_or(name)));
+            continue;
+        }
+
+        let method = form.get_list()?;
+        let name = safe_get(&method, 0);
+        let belongs = match (protocol.as_ref(), &*eval_expanded(&name, env)?) {
+            (Some(protocol), &AtomType::Multi(ref multi)) => multi.protocol() == protocol.get_symbol().ok(),
+            _ => false,
+        };
+        if !belongs {
+            let protocol = protocol.as_ref()
+                .map(|protocol| protocol.to_string())
+                .unwrap_or_else(|| "none".to_string());
+            return Err(AtomError::InvalidArgument(format!("{} is not a method of protocol {}",
+                                                          name,
+                                                          protocol)));
+        }
+        add_method(&name, type_atom.clone(), &method[1..], env)?;
+    }
+
+    Ok(c_nil())
+}
+
+// [lazy-seq body...] - body is evaluated the first time the sequence is used
+fn op_lazy_seq(args: &[AtomVal], env: &Env) -> AtomRet {
+    let thunk = c_afunc(env.clone(), vec![fn_clause(&c_list(&[]), &args[1..])?]);
+    Ok(c_lazy_seq(thunk, vec![]))
 }
 
-fn op_macro(args: &[AtomVal], env: &Env) -> AtomRet {
-    let result = eval(&safe_get(args, 2), env)?;
-    match *result {
-        AtomType::AFunc(ref fd) => op_def(&vec![c_nil(), safe_get(args, 1), c_macro(&fd)], env),
-        _ => unreachable!(),
+// Splits [defn name doc? {attrs}? fn-forms...] into the name, the meta
+// built from the docstring and attributes, and the index of the fn forms.
+fn definition_parts(args: &[AtomVal]) -> Result<(AtomVal, AtomMap, usize), AtomError> {
+    let (name_atom, mut meta) = name_and_meta(&safe_get(args, 1))?;
+    name_atom.get_symbol()?;
+
+    let mut index = 2;
+    if let AtomType::Str(_) = *safe_get(args, index) {
+        if index + 1 < args.len() {
+            meta.insert(c_keyword("doc"), args[index].clone());
+            index += 1;
+        }
+    }
+    if let AtomType::Map(ref attrs) = *safe_get(args, index) {
+        if index + 1 < args.len() {
+            for &(ref key, ref value) in attrs.iter() {
+                meta.insert(key.clone(), value.clone());
+            }
+            index += 1;
+        }
+    }
+
+    Ok((name_atom, meta, ::std::cmp::min(index, args.len())))
+}
+
+// [defn name doc? {attrs}? (params) body...]
+fn op_defn(args: &[AtomVal], env: &Env) -> AtomRet {
+    let (name_atom, meta, index) = definition_parts(args)?;
+    define(&name_atom, c_afunc(env.clone(), fn_clauses(&args[index..])?), meta, env)
+}
+
+// [defn- name doc? {attrs}? (params) body...] - defn visible only inside its
+// namespace
+fn op_defn_private(args: &[AtomVal], env: &Env) -> AtomRet {
+    let var = op_defn(args, env)?;
+    namespace::mark_private(env, definition_parts(args)?.0.get_symbol()?)?;
+    Ok(var)
+}
+
+// `(defmacro name (fn* ...))`, taking an already built function.
+fn is_macro_of_fn(forms: &[AtomVal]) -> bool {
+    forms.len() == 1 && (forms[0].is_form("fn*") || forms[0].is_form("fn"))
+}
+
+// [defmacro name doc? {attrs}? (params) body...] or [defmacro name (fn* ...)]
+fn op_macro(args: &[AtomVal], env: &Env) -> AtomRet {
+    let (name_atom, meta, index) = definition_parts(args)?;
+    let func = if is_macro_of_fn(&args[index..]) {
+        eval_expanded(&args[index], env)?
+    } else {
+        c_afunc(env.clone(), fn_clauses(&args[index..])?)
+    };
+
+    match *func {
+        AtomType::AFunc(ref fd) => define(&name_atom, c_macro(&fd), meta, env),
+        _ => Err(AtomError::InvalidType("AFunc".to_string(), func.format(true))),
+    }
+}
+
+// Symbol used in place of `name` inside a backquote template: `foo#` becomes
+// a gensym shared by the whole template, other symbols get qualified with the
+// namespace defining them, or the current one. None keeps the symbol as is.
+fn template_symbol(name: &Rc<String>, env: &Env, gensyms: &mut Gensyms) -> Option<AtomVal> {
+    if SPECIAL_FORMS.contains(&name.as_str()) || name.as_str() == "&" || name.contains('/') {
+        return None;
+    }
+
+    if name.len() > 1 && name.ends_with('#') {
+        let gensym = gensyms.entry(name.clone()).or_insert_with(|| {
+            c_symbol(&format!("{}__{}__auto__", &name[..name.len() - 1], next_gensym_id()))
+        });
+        return Some(gensym.clone());
+    }
+
+    env_defining_namespace(env, name)
+        .or_else(|| env_namespace(env))
+        .map(|ns| c_symbol(&format!("{}/{}", ns.name, name)))
+}
+
+type Gensyms = FnvHashMap<Rc<String>, AtomVal>;
+
+fn quoted(ast: AtomVal) -> AtomVal {
+    c_list(&[c_symbol("quote"), ast])
+}
+
+// `(unquote-splicing x)` at the current level.
+fn spliced(ast: &AtomVal, depth: usize) -> Option<AtomVal> {
+    match **ast {
+        AtomType::List(ref items) if depth == 1 && ast.is_form("unquote-splicing") => {
+            Some(items.get(1).cloned().unwrap_or_else(c_nil))
+        }
+        _ => None,
+    }
+}
+
+// Code building a list of `items`: consecutive elements are grouped into a
+// single `list` call and the groups joined with spliced values by `concat`.
+fn quasiquote_seq(items: &[AtomVal], depth: usize, env: &Env, gensyms: &mut Gensyms) -> AtomRet {
+    let mut segments = vec![c_symbol("rulsp.core/concat")];
+    let mut group = vec![c_symbol("rulsp.core/list")];
+
+    for item in items {
+        match spliced(item, depth) {
+            Some(value) => {
+                if group.len() > 1 {
+                    segments.push(c_list(&group));
+                    group.truncate(1);
+                }
+                segments.push(value);
+            }
+            None => group.push(quasiquote(item, depth, env, gensyms)?),
+        }
+    }
+
+    if segments.len() == 1 {
+        return Ok(c_list(&group));
+    }
+    if group.len() > 1 {
+        segments.push(c_list(&group));
+    }
+
+    Ok(c_list(&segments))
+}
+
+// Expands a backquote template nested `depth` backquotes deep into code
+// which builds it. Unquotes only apply at depth 1, deeper ones are rebuilt
+// with their own level decremented.
+fn quasiquote(ast: &AtomVal, depth: usize, env: &Env, gensyms: &mut Gensyms) -> AtomRet {
+    match **ast {
+        AtomType::Symbol(ref name) => {
+            Ok(quoted(template_symbol(name, env, gensyms).unwrap_or_else(|| ast.clone())))
+        }
+        AtomType::List(ref items) if items.len() == 2 => {
+            let items = items.as_slice();
+            let head = &items[0];
+            let nested = |marker: &str, depth: usize, gensyms: &mut Gensyms| -> AtomRet {
+                Ok(c_list(&[c_symbol("rulsp.core/list"),
+                            quoted(c_symbol(marker)),
+                            quasiquote(&items[1], depth, env, gensyms)?]))
+            };
+
+            if head.is_symbol("unquote") {
+                if depth == 1 {
+                    Ok(items[1].clone())
+                } else {
+                    nested("unquote", depth - 1, gensyms)
+                }
+            } else if head.is_symbol("unquote-splicing") {
+                if depth == 1 {
+                    Err(AtomError::InvalidOperation("unquote-splicing outside of a list".to_string()))
+                } else {
+                    nested("unquote-splicing", depth - 1, gensyms)
+                }
+            } else if head.is_symbol("backquote") {
+                nested("backquote", depth + 1, gensyms)
+            } else {
+                quasiquote_seq(&items, depth, env, gensyms)
+            }
+        }
+        AtomType::List(ref items) => quasiquote_seq(&items.as_slice(), depth, env, gensyms),
+        AtomType::Vec(ref items) => {
+            Ok(c_list(&[c_symbol("rulsp.core/vec"), quasiquote_seq(&items.as_slice(), depth, env, gensyms)?]))
+        }
+        AtomType::Map(ref map) => {
+            let mut code = vec![c_symbol("rulsp.core/hash-map")];
+            for &(ref key, ref value) in map.iter() {
+                code.push(quasiquote(key, depth, env, gensyms)?);
+                code.push(quasiquote(value, depth, env, gensyms)?);
+            }
+            Ok(c_list(&code))
+        }
+        _ => Ok(ast.clone()),
+    }
+}
+
+fn expand_backquote(template: &AtomVal, env: &Env) -> AtomRet {
+    quasiquote(template, 1, env, &mut FnvHashMap::default())
+}
+
+// [backquote template]
+fn op_backquote(args: &[AtomVal], env: &Env) -> AtomRet {
+    eval(&expand_backquote(&safe_get(args, 1), env)?, env)
+}
+
+// Looks a symbol up in env, falling back to namespace qualified names.
+fn lookup(env: &Env, symbol: &AtomVal) -> Option<AtomVal> {
+    env_get(env, symbol).or_else(|| match **symbol {
+        AtomType::Symbol(ref name) => namespace::resolve(env, name).ok(),
+        _ => None,
+    })
+}
+
+fn is_macro_call(ast: &AtomVal, env: &Env) -> bool {
+    match **ast {
+        AtomType::List(ref args) => {
+            if let Some(value) = args.first().and_then(|head| lookup(&env, head)) {
+                match *value {
+                    AtomType::AFunc(ref fd) => fd.is_macro,
+                    _ => false,
+                }
+            } else {
+                false
+            }
+        }
+        _ => false,
+    }
+}
+
+pub fn macroexpand_1(ast: &AtomVal, env: &Env) -> AtomRet {
+    if !is_macro_call(ast, env) {
+        return Ok(ast.clone());
+    }
+
+    let args = &ast.get_list()?;
+    match lookup(&env, &args[0]) {
+        Some(f) => f.apply(&args[1..]),
+        None => Ok(ast.clone()),
+    }
+}
+
+fn op_macroexpand(ast: &AtomVal, env: &Env) -> AtomRet {
+    let mut ast = ast.clone();
+    while is_macro_call(&ast, env) {
+        ast = macroexpand_1(&ast, env)?;
+    }
+
+    Ok(ast)
+}
+
+fn macroexpand_each(items: &[AtomVal], env: &Env) -> Result<Vec<AtomVal>, AtomError> {
+    let mut expanded = Vec::with_capacity(items.len());
+    for item in items {
+        expanded.push(macroexpand_all(item, env)?);
+    }
+
+    Ok(expanded)
+}
+
+// Expands function bodies, leaving params as they are.
+fn macroexpand_fn_forms(forms: &[AtomVal], env: &Env) -> Result<Vec<AtomVal>, AtomError> {
+    if !is_multi_arity(forms) {
+        let mut expanded = forms.iter().take(1).cloned().collect::<Vec<_>>();
+        if !forms.is_empty() {
+            expanded.extend(macroexpand_each(&forms[1..], env)?);
+        }
+        return Ok(expanded);
+    }
+
+    let mut expanded = Vec::with_capacity(forms.len());
+    for clause in forms {
+        let clause = &clause.get_list()?;
+        let mut expanded_clause = vec![clause[0].clone()];
+        expanded_clause.extend(macroexpand_each(&clause[1..], env)?);
+        expanded.push(c_list(&expanded_clause));
+    }
+
+    Ok(expanded)
+}
+
+// Expands macros and backquotes in the whole tree. Special forms are only
+// expanded where they hold code: quoted data, `fn*` params, names given to
+// `def` and loop binding names are left untouched.
+pub fn macroexpand_all(ast: &AtomVal, env: &Env) -> AtomRet {
+    let ast = op_macroexpand(ast, env)?;
+    let list = match *ast {
+        AtomType::List(ref list) => list.as_slice(),
+        AtomType::Vec(ref items) => return Ok(c_vec(&macroexpand_each(&items.as_slice(), env)?)),
+        AtomType::Map(ref map) => {
+            let mut expanded = AtomMap::new();
+            for &(ref key, ref value) in map.iter() {
+                expanded.insert(macroexpand_all(key, env)?, macroexpand_all(value, env)?);
+            }
+            return Ok(c_map(expanded));
+        }
+        AtomType::Set(ref set) => {
+            let items = set.iter().cloned().collect::<Vec<_>>();
+            return Ok(c_set(AtomSet::from_items(&macroexpand_each(&items, env)?)));
+        }
+        _ => return Ok(ast.clone()),
+    };
+    let args = &*list;
+
+    // number of leading elements kept as they are
+    let verbatim = match *safe_get(args, 0) {
+        AtomType::Symbol(ref name) => {
+            match name.as_str() {
+                "quote" | "ns" | "var" | "defrecord" | "defprotocol" => args.len(),
+                "backquote" => return macroexpand_all(&expand_backquote(&safe_get(args, 1), env)?, env),
+                "def" | "def-" | "set!" | "defmulti" => 2,
+                "fn*" | "fn" => {
+                    let mut expanded = vec![args[0].clone()];
+                    expanded.extend(macroexpand_fn_forms(&args[1..], env)?);
+                    return Ok(c_list(&expanded));
+                }
+                "defmethod" if args.len() > 3 => {
+                    let mut expanded = args[..3].to_vec();
+                    expanded.extend(macroexpand_fn_forms(&args[3..], env)?);
+                    return Ok(c_list(&expanded));
+                }
+                "extend-type" => {
+                    let mut expanded = vec![];
+                    for arg in args {
+                        match **arg {
+                            AtomType::List(ref method) if !method.is_empty() => {
+                                let method = method.as_slice();
+                                let mut expanded_method = vec![method[0].clone()];
+                                expanded_method.extend(macroexpand_fn_forms(&method[1..], env)?);
+                                expanded.push(c_list(&expanded_method));
+                            }
+                            _ => expanded.push(arg.clone()),
+                        }
+                    }
+                    return Ok(c_list(&expanded));
+                }
+                "defn" | "defn-" | "defmacro" => {
+                    let (_, _, index) = definition_parts(args)?;
+                    let mut expanded = args[..index].to_vec();
+                    if is_macro_of_fn(&args[index..]) {
+                        expanded.extend(macroexpand_each(&args[index..], env)?);
+                    } else {
+                        expanded.extend(macroexpand_fn_forms(&args[index..], env)?);
+                    }
+                    return Ok(c_list(&expanded));
+                }
+                "case" => return case_table(args, env),
+                "case*" => 3,
+                "match" => {
+                    let mut expanded = vec![args[0].clone(), macroexpand_all(&safe_get(args, 1), env)?];
+                    for (pattern, guard, expr) in match_clauses(args)? {
+                        expanded.push(pattern);
+                        if let Some(guard) = guard {
+                            expanded.push(c_keyword("when"));
+                            expanded.push(macroexpand_all(&guard, env)?);
+                        }
+                        expanded.push(macroexpand_all(&expr, env)?);
+                    }
+                    return Ok(c_list(&expanded));
+                }
+                "let" | "let*" | "if-let" | "when-let" | "binding" => {
+                    let mut bindings = Vec::new();
+                    for (pattern, value) in let_bindings(&safe_get(args, 1))? {
+                        bindings.push(pattern);
+                        bindings.push(macroexpand_all(&value, env)?);
+                    }
+
+                    let mut expanded = vec![args[0].clone(), c_vec(&bindings)];
+                    expanded.extend(macroexpand_each(&args[2..], env)?);
+                    return Ok(c_list(&expanded));
+                }
+                "loop" => {
+                    let mut bindings = Vec::new();
+                    for chunk in safe_get(args, 1).get_list()?.chunks(2) {
+                        bindings.push(chunk[0].clone());
+                        bindings.extend(macroexpand_each(&chunk[1..], env)?);
+                    }
+
+                    let mut expanded = vec![args[0].clone(), c_list(&bindings)];
+                    expanded.extend(macroexpand_each(&args[2..], env)?);
+                    return Ok(c_list(&expanded));
+                }
+                _ => 0,
+            }
+        }
+        _ => 0,
+    };
+
+    let verbatim = ::std::cmp::min(verbatim, args.len());
+    let mut expanded = args[..verbatim].to_vec();
+    expanded.extend(macroexpand_each(&args[verbatim..], env)?);
+
+    Ok(c_list(&expanded))
+}
+
+fn is_truthy(value: &AtomVal) -> bool {
+    match **value {
+        AtomType::Nil => false,
+        _ => true,
+    }
+}
+
+// Value of the last form, each one evaluated in order.
+fn eval_body(forms: &[AtomVal], env: &Env) -> AtomRet {
+    let mut result = c_nil();
+    for form in forms {
+        result = eval_expanded(form, env)?;
+    }
+
+    Ok(result)
+}
+
+fn op_if(args: &[AtomVal], env: &Env) -> AtomRet {
+    if args.len() > 4 {
+        return Err(AtomError::InvalidArgument("if takes a test and at most two branches"
+            .to_string()));
+    }
+
+    let result = eval_expanded(&safe_get(args, 1), env)?;
+    match *result {
+        AtomType::Nil => eval_expanded(&safe_get(args, 3), env),
+        _ => eval_expanded(&safe_get(args, 2), env),
+    }
+}
+
+// [when test body...] or [when-not test body...]
+fn op_when(args: &[AtomVal], env: &Env, expected: bool) -> AtomRet {
+    if is_truthy(&eval_expanded(&safe_get(args, 1), env)?) == expected {
+        eval_body(rest_from(args, 2), env)
+    } else {
+        Ok(c_nil())
+    }
+}
+
+// [cond test expr...]
+fn op_cond(args: &[AtomVal], env: &Env) -> AtomRet {
+    if args.len() % 2 == 0 {
+        return Err(AtomError::InvalidArgument("cond is missing expression for one of the tests"
+            .to_string()));
+    }
+
+    for clause in args[1..].chunks(2) {
+        if is_truthy(&eval_expanded(&clause[0], env)?) {
+            return eval_expanded(&clause[1], env);
+        }
+    }
+
+    Ok(c_nil())
+}
+
+// [and form...] stops at the first nil, [or form...] at the first other value
+fn op_and_or(args: &[AtomVal], env: &Env, stop_when: bool) -> AtomRet {
+    let mut result = if stop_when { c_nil() } else { c_int(1) };
+    for form in &args[1..] {
+        result = eval_expanded(form, env)?;
+        if is_truthy(&result) == stop_when {
+            break;
+        }
+    }
+
+    Ok(result)
+}
+
+// [if-let [pattern value] then else] or [when-let [pattern value] body...]
+fn op_if_let(args: &[AtomVal], env: &Env) -> AtomRet {
+    let bindings = let_bindings(&safe_get(args, 1))?;
+    if bindings.len() != 1 {
+        return Err(AtomError::InvalidArgument(format!("{} takes exactly one binding", args[0])));
+    }
+
+    let (ref pattern, ref value) = bindings[0];
+    let value = eval_expanded(value, env)?;
+    let is_when = args[0].is_symbol("when-let");
+    if !is_truthy(&value) {
+        return if is_when { Ok(c_nil()) } else { eval_expanded(&safe_get(args, 3), env) };
+    }
+
+    let let_env = c_env(Some(env.clone()));
+    env_destructure(&let_env, pattern, &value)?;
+    if is_when {
+        eval_body(rest_from(args, 2), &let_env)
+    } else {
+        eval_expanded(&safe_get(args, 2), &let_env)
+    }
+}
+
+// Turns [case value constant expr... default?] into [case* value table
+// default?], `table` mapping each constant to its expression and looked up
+// by hash. A list of constants matches any of them.
+fn case_table(args: &[AtomVal], env: &Env) -> AtomRet {
+    let mut table = AtomMap::new();
+    let clauses = rest_from(args, 2);
+    for clause in clauses.chunks(2).filter(|clause| clause.len() == 2) {
+        let expr = macroexpand_all(&clause[1], env)?;
+        match *clause[0] {
+            AtomType::List(ref constants) => {
+                for constant in constants {
+                    table.insert(constant.clone(), expr.clone());
+                }
+            }
+            _ => table.insert(clause[0].clone(), expr),
+        }
+    }
+
+    let value = macroexpand_all(&safe_get(args, 1), env)?;
+    let mut expanded = vec![c_symbol("case*"), value, c_map(table.hashed())];
+    if clauses.len() % 2 == 1 {
+        expanded.push(macroexpand_all(&clauses[clauses.len() - 1], env)?);
+    }
+
+    Ok(c_list(&expanded))
+}
+
+// [case* value table default?]
+fn op_case(args: &[AtomVal], env: &Env) -> AtomRet {
+    let value = eval_expanded(&safe_get(args, 1), env)?;
+    let expr = match *safe_get(args, 2) {
+        AtomType::Map(ref table) => table.get(&value).cloned(),
+        _ => None,
+    };
+
+    match expr {
+        Some(expr) => eval_expanded(&expr, env),
+        None if args.len() > 3 => eval_expanded(&args[3], env),
+        None => Err(AtomError::InvalidArgument(format!("no case matching {}", value))),
+    }
+}
+
+// Pattern, guard and expression of each [match value pattern :when guard? expr...] clause.
+fn match_clauses(args: &[AtomVal]) -> Result<Vec<(AtomVal, Option<AtomVal>, AtomVal)>, AtomError> {
+    let mut clauses = Vec::new();
+    let mut forms = rest_from(args, 2).iter();
+    while let Some(pattern) = forms.next() {
+        let mut guard = None;
+        let mut expr = forms.next();
+        if expr.map_or(false, |expr| expr.is_keyword("when")) {
+            guard = forms.next().cloned();
+            expr = forms.next();
+        }
+
+        let expr = expr.cloned().ok_or_else(|| {
+            AtomError::InvalidArgument(format!("match is missing expression for {}", pattern))
+        })?;
+        clauses.push((pattern.clone(), guard, expr));
+    }
+
+    Ok(clauses)
+}
+
+fn match_seq(patterns: &[AtomVal], items: &[AtomVal], bindings: &mut Vec<(AtomVal, AtomVal)>) -> bool {
+    let (fixed, rest) = match patterns.iter().position(|v| v.is_symbol("&")) {
+        Some(index) => (index, Some(safe_get(patterns, index + 1))),
+        None => (patterns.len(), None),
+    };
+
+    if items.len() < fixed || (rest.is_none() && items.len() > fixed) {
+        return false;
+    }
+    if !patterns[..fixed].iter().zip(items).all(|(pattern, item)| match_pattern(pattern, item, bindings)) {
+        return false;
+    }
+
+    match rest {
+        Some(ref rest) if items.len() > fixed => match_pattern(rest, &c_list(&items[fixed..]), bindings),
+        Some(ref rest) => match_pattern(rest, &c_nil(), bindings),
+        None => true,
     }
 }
 
-fn is_macro_call(ast: &AtomVal, env: &Env) -> bool {
-    match **ast {
-        AtomType::List(ref args) => {
-            if let Some(value) = env_get(&env, &args[0]) {
-                match *value {
-                    AtomType::AFunc(ref fd) => fd.is_macro,
-                    _ => false,
-                }
-            } else {
-                false
+// Whether `value` has the shape of `pattern`, pushing the symbols it binds
+// to `bindings`. Symbols other than `_` bind anything, `'sym` is a literal
+// symbol, lists and vectors match their own kind element by element and maps
+// match the given keys. Other patterns are compared to the value.
+fn match_pattern(pattern: &AtomVal, value: &AtomVal, bindings: &mut Vec<(AtomVal, AtomVal)>) -> bool {
+    match **pattern {
+        AtomType::Symbol(ref name) => {
+            if name.as_str() != "_" {
+                bindings.push((pattern.clone(), value.clone()));
             }
+            true
         }
-        _ => false,
+        AtomType::List(ref patterns) if pattern.is_form("quote") => patterns.get(1) == Some(value),
+        AtomType::List(ref patterns) => {
+            match **value {
+                AtomType::List(ref items) => match_seq(&patterns.as_slice(), &items.as_slice(), bindings),
+                _ => false,
+            }
+        }
+        AtomType::Vec(ref patterns) => {
+            match **value {
+                AtomType::Vec(ref items) => match_seq(&patterns.as_slice(), &items.as_slice(), bindings),
+                _ => false,
+            }
+        }
+        AtomType::Map(ref patterns) => {
+            let map = match **value {
+                AtomType::Map(ref map) => map,
+                AtomType::Record(ref record) => record.values(),
+                _ => return false,
+            };
+            patterns.iter().all(|&(ref key, ref pattern)| {
+                map.get(key).map_or(false, |item| match_pattern(pattern, item, bindings))
+            })
+        }
+        _ => pattern == value,
     }
 }
 
-fn op_macroexpand(ast: &AtomVal, env: &Env) -> AtomRet {
-    // println!("IS MACRO CALL: {:?}", ast);
-    let mut ast = ast.clone();
-    while is_macro_call(&ast, env) {
-        let new_ast = ast.clone();
-
-        let args = match *new_ast {
-            AtomType::List(ref args) => args,
-            _ => break,
-        };
+// [match value pattern :when guard? expr...] - evaluates the expression of
+// the first clause matching value, with the pattern's symbols bound
+fn op_match(args: &[AtomVal], env: &Env) -> AtomRet {
+    let value = eval_expanded(&safe_get(args, 1), env)?;
+    for (pattern, guard, expr) in match_clauses(args)? {
+        let mut bindings = Vec::new();
+        if !match_pattern(&pattern, &value, &mut bindings) {
+            continue;
+        }
 
-        if let Some(f) = env_get(&env, &args[0]) {
-            ast = f.apply(&args[1..])?;
-        } else {
-            break;
+        let match_env = c_env(Some(env.clone()));
+        for (symbol, item) in bindings {
+            env_set(&match_env, &symbol, item)?;
         }
+        if let Some(guard) = guard {
+            if !is_truthy(&eval_expanded(&guard, &match_env)?) {
+                continue;
+            }
+        }
+
+        return eval_expanded(&expr, &match_env);
     }
 
-    // println!("EXPANDED AST: {:?}", ast);
-    Ok(ast)
+    Err(AtomError::InvalidArgument(format!("no pattern matching {}", value)))
 }
 
-fn op_if(args: &[AtomVal], env: &Env) -> AtomRet {
-    let result = eval(&safe_get(args, 1), env)?;
-    match *result {
-        AtomType::Nil => eval(&safe_get(args, 3), env),
-        _ => eval(&safe_get(args, 2), env),
+// Pattern and value pairs of `[a 1 b 2]`, or of the older `((a 1) (b 2))`.
+fn let_bindings(bindings: &AtomVal) -> Result<Vec<(AtomVal, AtomVal)>, AtomError> {
+    let pairs = match **bindings {
+        AtomType::Vec(ref items) => {
+            let items = items.as_slice();
+            if items.len() % 2 == 1 {
+                return Err(AtomError::InvalidArgument("let is missing value for one of the \
+                                                       bindings"
+                    .to_string()));
+            }
+            items.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect()
+        }
+        AtomType::List(ref items) => {
+            let mut pairs = Vec::with_capacity(items.len());
+            for pair in items {
+                let pair = &pair.get_list()?;
+                pairs.push((safe_get(pair, 0), safe_get(pair, 1)));
+            }
+            pairs
+        }
+        _ => return Err(AtomError::InvalidType("Vec".to_string(), bindings.format(true))),
+    };
+
+    Ok(pairs)
+}
+
+// [let [pattern value...] body...] - each value sees the bindings before it
+fn op_let(args: &[AtomVal], env: &Env) -> AtomRet {
+    let let_env = c_env(Some(env.clone()));
+    for (pattern, value) in let_bindings(&safe_get(args, 1))? {
+        let value = eval_expanded(&value, &let_env)?;
+        env_destructure(&let_env, &pattern, &value)?;
     }
+
+    eval_body(rest_from(args, 2), &let_env)
 }
 
 // [loop (args...) (body)]
@@ -86,7 +1024,7 @@ fn op_loop(args: &[AtomVal], env: &Env) -> AtomRet {
 
     let ref body = safe_get(args, 2);
     let _loop_args = safe_get(args, 1);
-    let loop_args = _loop_args.get_list()?;
+    let loop_args = &_loop_args.get_list()?;
 
     if loop_args.len() % 2 == 1 {
         return Err(AtomError::InvalidArgument("Loop is missing value for one of the \
@@ -107,13 +1045,13 @@ fn op_loop(args: &[AtomVal], env: &Env) -> AtomRet {
     let mut result = None;
     loop {
         env_bind(&env, &arguments_names, &arguments_values)?;
-        result = Some(eval(body, &env)?);
+        result = Some(eval_expanded(body, &env)?);
 
         if let Some(ref result) = result {
-            match result.get_list() {
-                Ok(list) => {
-                    if safe_get(list, 0).is_symbol("recur") {
-                        arguments_values = eval_list_elements(&list[1..], &env)?;
+            match **result {
+                AtomType::List(ref list) => {
+                    if result.is_form("recur") {
+                        arguments_values = eval_list_elements(&list.rest().as_slice(), &env)?;
                     } else {
                         break;
                     }
@@ -130,7 +1068,7 @@ fn op_loop(args: &[AtomVal], env: &Env) -> AtomRet {
 }
 
 pub fn eval_exp(ast: &AtomVal, env: &Env) -> AtomRet {
-    let args = ast.get_list()?;
+    let args = &ast.get_list()?;
     let op_name = match args.get(0) {
         None => return Ok(ast.clone()),
         Some(op) => {
@@ -143,43 +1081,127 @@ pub fn eval_exp(ast: &AtomVal, env: &Env) -> AtomRet {
 
     match op_name {
         "quote" => op_quote(args),
-        "p_env" => {
-            println!("{:?}", env);
-            Ok(c_nil())
-        }
         "def" => op_def(args, env),
+        "def-" => op_def_private(args, env),
+        "set!" => op_set(args, env),
+        "var" => op_var(args, env),
+        "binding" => op_binding(args, env),
+        "with-meta" => eval_expanded(&safe_get(args, 1), env),
+        "ns" => op_ns(args, env),
+        "in-ns" => op_in_ns(args, env),
+        "backquote" => op_backquote(args, env),
         "if" => op_if(args, env),
-        "fn*" => op_lambda(args, env),
+        "when" => op_when(args, env, true),
+        "when-not" => op_when(args, env, false),
+        "cond" => op_cond(args, env),
+        "and" => op_and_or(args, env, false),
+        "or" => op_and_or(args, env, true),
+        "if-let" | "when-let" => op_if_let(args, env),
+        "case" => eval_expanded(&case_table(args, env)?, env),
+        "case*" => op_case(args, env),
+        "match" => op_match(args, env),
+        "fn*" | "fn" => op_lambda(args, env),
+        "lazy-seq" => op_lazy_seq(args, env),
+        "defrecord" => op_defrecord(args, env),
+        "defmulti" => op_defmulti(args, env),
+        "defmethod" => op_defmethod(args, env),
+        "defprotocol" => op_defprotocol(args, env),
+        "extend-type" => op_extend_type(args, env),
+        "defn" => op_defn(args, env),
+        "defn-" => op_defn_private(args, env),
+        "let" | "let*" => op_let(args, env),
         "loop" => op_loop(args, env),
         "recur" => Ok(ast.clone()),
         "defmacro" => op_macro(args, env),
-        "eval" => eval(&eval(&safe_get(args, 1), env)?, env),
+        "eval" => eval(&eval_expanded(&safe_get(args, 1), env)?, env),
         "do" => {
             let evaled_args = eval_list_elements(&args[1..], env)?;
             Ok(evaled_args.last().cloned().unwrap_or_else(c_nil))
         }
-        "macroexpand" => op_macroexpand(&eval_exp(&safe_get(args, 1), env)?, env),
+        "macroexpand-1" => macroexpand_1(&eval_expanded(&safe_get(args, 1), env)?, env),
+        "macroexpand" => op_macroexpand(&eval_expanded(&safe_get(args, 1), env)?, env),
+        "macroexpand-all" => macroexpand_all(&eval_expanded(&safe_get(args, 1), env)?, env),
+        "load" => load_file(eval_expanded(&safe_get(args, 1), env)?.get_str()?, env),
+        "require" => {
+            for name in eval_list_elements(&args[1..], env)? {
+                require(&name, env)?;
+            }
+            Ok(c_nil())
+        }
         // Some function call with evaled arguments
         _ => {
-            let evaled_args = eval_ast(ast, env)?;
-            let args = match evaled_args.get_list() {
-                Ok(args) => args,
-                _ => return Err(AtomError::InvalidOperation(op_name.to_string())),
-            };
+            let subject_func = eval_expanded(&args[0], env)?;
+            match *subject_func {
+                // macro defined after the code calling it was expanded
+                AtomType::AFunc(ref fd) if fd.is_macro && is_macro_call(ast, env) => {
+                    return eval_expanded(&late_expansion(ast, &subject_func, env)?, env);
+                }
+                _ => {}
+            }
 
-            trace!("fn=eval_exp op_name={} args={:?}", op_name, &args[1..]);
-            let subject_func = &args[0];
-            subject_func.apply(&args[1..])
+            let args = eval_list_elements(&args[1..], env)?;
+            trace!("fn=eval_exp op_name={} args={:?}", op_name, &args);
+            subject_func.apply(&args)
         }
 
     }
 }
 
+// Expansion of a call to a macro defined after the code calling it was
+// expanded, kept while both the call form and the macro are alive.
+struct LateExpansion {
+    form: Weak<AtomType>,
+    macro_fn: Weak<AtomType>,
+    expanded: AtomVal,
+}
+
+// Entries of dropped forms are removed once there are more than this many.
+const LATE_EXPANSIONS_LIMIT: usize = 256;
+
+thread_local!(static LATE_EXPANSIONS: RefCell<FnvHashMap<usize, LateExpansion>> =
+                  RefCell::new(FnvHashMap::default()));
+
+// Expands `ast`, a call to `macro_fn`, once and reuses the expansion on later
+// calls, unless the macro was redefined since.
+fn late_expansion(ast: &AtomVal, macro_fn: &AtomVal, env: &Env) -> AtomRet {
+    let key = &**ast as *const AtomType as usize;
+    let cached = LATE_EXPANSIONS.with(|cache| {
+        cache.borrow().get(&key).and_then(|entry| {
+            let same_form = entry.form.upgrade().map_or(false, |form| Rc::ptr_eq(&form, ast));
+            let same_macro = entry.macro_fn.upgrade().map_or(false, |f| Rc::ptr_eq(&f, macro_fn));
+            if same_form && same_macro {
+                Some(entry.expanded.clone())
+            } else {
+                None
+            }
+        })
+    });
+    if let Some(expanded) = cached {
+        return Ok(expanded);
+    }
+
+    let expanded = macroexpand_all(ast, env)?;
+    LATE_EXPANSIONS.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if cache.len() >= LATE_EXPANSIONS_LIMIT {
+            cache.retain(|_, entry| entry.form.upgrade().is_some());
+        }
+        cache.insert(key,
+                     LateExpansion {
+                         form: Rc::downgrade(ast),
+                         macro_fn: Rc::downgrade(macro_fn),
+                         expanded: expanded.clone(),
+                     });
+    });
+
+    Ok(expanded)
+}
+
 fn eval_list_elements(list: &[AtomVal], env: &Env) -> Result<Vec<AtomVal>, AtomError> {
     let mut evaled_elements = Vec::with_capacity(list.len());
 
     for element in list {
-        evaled_elements.push(eval(element, env)?);
+        evaled_elements.push(eval_expanded(element, env)?);
     }
 
     Ok(evaled_elements)
@@ -191,27 +1213,72 @@ fn eval_ast(ast: &AtomVal, env: &Env) -> AtomRet {
     match **ast {
         AtomType::Symbol(ref name) => {
             if let Some(atom) = env_get(&env, &ast) {
-                Ok(atom)
+                Ok(symbol_value(atom))
             } else {
-                Err(AtomError::UndefinedSymbol(name.to_string()))
+                namespace::resolve(env, name).map(symbol_value)
+            }
+        }
+        AtomType::List(ref args) => Ok(c_list(&eval_list_elements(&args.as_slice(), env)?)),
+        AtomType::Vec(ref args) => Ok(c_vec(&eval_list_elements(&args.as_slice(), env)?)),
+        AtomType::Map(ref map) => {
+            let mut evaled = AtomMap::new();
+            for &(ref key, ref value) in map.iter() {
+                evaled.insert(eval_expanded(key, env)?, eval_expanded(value, env)?);
+            }
+            Ok(c_map(evaled))
+        }
+        AtomType::Set(ref set) => {
+            let mut evaled = AtomSet::new();
+            for item in set.iter() {
+                evaled.insert(eval_expanded(item, env)?);
             }
+            Ok(c_set(evaled))
         }
-        AtomType::List(ref args) => Ok(c_list(&eval_list_elements(args, env)?)),
         _ => Ok(ast.clone()),
     }
 }
 
-pub fn eval(ast: &AtomVal, env: &Env) -> AtomRet {
+// Evaluates code that already went through `macroexpand_all`.
+pub fn eval_expanded(ast: &AtomVal, env: &Env) -> AtomRet {
     match **ast {
-        AtomType::List(_) => {
-            let ast = op_macroexpand(ast, env)?;
-            match *ast {
-                AtomType::List(_) => eval_exp(&ast, env),
-                _ => eval_ast(&ast, env),
+        AtomType::List(_) => eval_exp(ast, env),
+        _ => eval_ast(ast, env),
+    }
+}
+
+// Expands all macros in `ast` once, then evaluates it. Forms of a top level
+// `do` are handled one by one, so a macro can be used right after its
+// definition.
+pub fn eval(ast: &AtomVal, env: &Env) -> AtomRet {
+    let ast = op_macroexpand(ast, env)?;
+    if let AtomType::List(ref args) = *ast {
+        if ast.is_form("do") {
+            let mut result = c_nil();
+            for form in args.iter().skip(1) {
+                result = eval(form, env)?;
             }
+            return Ok(result);
         }
-        _ => eval_ast(ast, env),
     }
+
+    eval_expanded(&macroexpand_all(&ast, env)?, env)
+}
+
+pub fn read_str(str: &str) -> Result<Vec<AtomVal>, AtomError> {
+    let tokens = lex(str).map_err(|err| AtomError::Syntax(format!("{}", err)))?;
+    Parser::new(&tokens).start_all().map_err(|err| AtomError::Syntax(format!("{}", err)))
+}
+
+// Evaluates every form in `str`, returning the value of the last one. Forms
+// are evaluated in the current namespace, so `ns` affects the ones after it.
+pub fn eval_str_all(str: &str, env: &Env) -> AtomRet {
+    let mut result = c_nil();
+    for ast in read_str(str)? {
+        result = eval(&ast, &namespace::top_level_env(env))?;
+        gc::maybe_collect();
+    }
+
+    Ok(result)
 }
 
 pub fn eval_str(str: &str, env: &Env) -> AtomRet {
@@ -254,12 +1321,12 @@ pub fn eval_str(str: &str, env: &Env) -> AtomRet {
 
 #[cfg(test)]
 mod tests {
-    use super::eval;
-    use data::{c_symbol, c_int, c_list, AtomRet, AtomError};
+    use super::{eval, eval_str_all, read_str, macroexpand_all};
+    use data::{c_symbol, c_int, c_list, c_map, realize_printed, AtomType, AtomRet, AtomError};
     use env::Env;
 
     pub fn print(v: AtomRet) -> String {
-        match v {
+        match v.and_then(|value| realize_printed(&value).map(|_| value)) {
             Ok(ref atom) => format!("{}", atom),
             Err(err) => format!("{}", err),
         }
@@ -309,4 +1376,501 @@ mod tests {
         assert_eq!("2",
                    print(eval(&c_list(&[c_symbol("/"), c_int(4), c_int(2)]), &env())));
     }
+
+    #[test]
+    fn eval_all_returns_last_form() {
+        let env = env();
+
+        assert_eq!("3", print(eval_str_all("(def a 1) (def b 2) (+ a b)", &env)));
+    }
+
+    #[test]
+    fn backquote_qualifies_free_symbols() {
+        let env = env();
+
+        assert_eq!("(rulsp.core/first user/x (quote &) 1)",
+                   print(eval_str_all("`(first x (quote &) ~(+ 0 1))", &env)));
+    }
+
+    #[test]
+    fn macroexpand_variants() {
+        let env = env();
+        eval_str_all("(defmacro unless (fn* (c x) (list 'if c nil x)))", &env).unwrap();
+        eval_str_all("(defmacro unless2 (fn* (c x) (list 'unless c x)))", &env).unwrap();
+
+        assert_eq!("(unless 1 (unless2 2 3))",
+                   print(eval_str_all("(macroexpand-1 '(unless2 1 (unless2 2 3)))", &env)));
+        assert_eq!("(if 1 nil (unless2 2 3))",
+                   print(eval_str_all("(macroexpand '(unless2 1 (unless2 2 3)))", &env)));
+        assert_eq!("(if 1 nil (if 2 nil 3))",
+                   print(eval_str_all("(macroexpand-all '(unless2 1 (unless2 2 3)))", &env)));
+    }
+
+    #[test]
+    fn macroexpand_all_respects_special_forms() {
+        let env = env();
+        eval_str_all("(defmacro unless (fn* (c x) (list 'if c nil x)))", &env).unwrap();
+
+        assert_eq!("(fn* (unless) (if 1 nil 2))",
+                   print(eval_str_all("(macroexpand-all '(fn* (unless) (unless 1 2)))", &env)));
+        assert_eq!("(quote (unless 1 2))",
+                   print(eval_str_all("(macroexpand-all ''(unless 1 2))", &env)));
+        assert_eq!("(loop (unless (if 1 nil 2)) unless)",
+                   print(eval_str_all("(macroexpand-all '(loop (unless (unless 1 2)) unless))", &env)));
+    }
+
+    #[test]
+    fn fn_body_expanded_once() {
+        let env = env();
+        eval_str_all("(defmacro m (fn* () 1))", &env).unwrap();
+        eval_str_all("(def f (fn* () (m)))", &env).unwrap();
+        eval_str_all("(defmacro m (fn* () 2))", &env).unwrap();
+
+        assert_eq!("1", print(eval_str_all("(f)", &env)));
+        assert_eq!("2", print(eval_str_all("(m)", &env)));
+    }
+
+    #[test]
+    fn macro_defined_after_use() {
+        let env = env();
+        eval_str_all("(def f (fn* () (later 1)))", &env).unwrap();
+        eval_str_all("(defmacro later (fn* (x) (list '+ x 1)))", &env).unwrap();
+
+        assert_eq!("2", print(eval_str_all("(f)", &env)));
+    }
+
+    #[test]
+    fn macro_defined_after_use_expands_once() {
+        let env = env();
+        eval_str_all("(def f (fn* () (later (undefined-name))))
+                      (defmacro later (fn* (& forms) (list (quote quote) (gensym))))",
+                     &env)
+            .unwrap();
+
+        // a new expansion would quote a different gensym
+        assert_eq!("1", print(eval_str_all("(= (f) (f))", &env)));
+        eval_str_all("(defmacro later (fn* (& forms) 3))", &env).unwrap();
+        assert_eq!("3", print(eval_str_all("(f)", &env)));
+    }
+
+    #[test]
+    fn macro_used_in_same_do() {
+        let env = env();
+
+        assert_eq!("3",
+                   print(eval_str_all("(do (defmacro two (fn* () 2)) (+ 1 (two)))", &env)));
+    }
+
+    #[test]
+    fn let_bindings_are_sequential() {
+        let env = env();
+
+        assert_eq!("3", print(eval_str_all("(let [a 1 b (+ a 1)] (+ a b))", &env)));
+        assert_eq!("2", print(eval_str_all("(let* ((a 1) (a (+ a 1))) a)", &env)));
+    }
+
+    #[test]
+    fn let_destructuring() {
+        let env = env();
+        eval_str_all("(def pair [1 2]) (def m {:a 3 :b 4})", &env).unwrap();
+
+        assert_eq!("10",
+                   print(eval_str_all("(let [[x y] pair {:keys [a b]} m] (+ x y a b))", &env)));
+        assert_eq!("((2 3) [1 2 3])",
+                   print(eval_str_all("(let [[_ & xs :as all] [1 2 3]] (list xs all))", &env)));
+        assert_eq!("(3 nil)", print(eval_str_all("(let [{a :a c :c} m] (list a c))", &env)));
+    }
+
+    #[test]
+    fn let_odd_bindings() {
+        let env = env();
+
+        assert!(eval_str_all("(let [a 1 b] a)", &env).is_err());
+    }
+
+    #[test]
+    fn fn_arity_errors_name_the_function() {
+        let env = env();
+        eval_str_all("(def add (fn* (a b) (+ a b)))", &env).unwrap();
+
+        assert_eq!("invalid argument: wrong number of args (1) passed to: add",
+                   print(eval_str_all("(add 1)", &env)));
+        assert_eq!("invalid argument: wrong number of args (3) passed to: add",
+                   print(eval_str_all("(add 1 2 3)", &env)));
+        assert_eq!("invalid argument: wrong number of args (0) passed to: fn*",
+                   print(eval_str_all("((fn* (a & xs) a))", &env)));
+    }
+
+    #[test]
+    fn fn_params_destructuring() {
+        let env = env();
+        eval_str_all("(def f (fn* ([a [b c]] {:keys [d]}) (list a b c d)))", &env).unwrap();
+        eval_str_all("(def g (fn* (x & {:keys [y z]}) (list x y z)))", &env).unwrap();
+
+        assert_eq!("(1 2 3 4)", print(eval_str_all("(f [1 [2 3]] {:d 4})", &env)));
+        assert_eq!("(1 nil 3)", print(eval_str_all("(g 1 :z 3)", &env)));
+        assert_eq!("(1 nil nil)", print(eval_str_all("(g 1)", &env)));
+    }
+
+    #[test]
+    fn multi_arity_fn() {
+        let env = env();
+        eval_str_all("(def f (fn ([] 0) ([x] x) ([x & xs] (count xs))))", &env).unwrap();
+
+        assert_eq!("0", print(eval_str_all("(f)", &env)));
+        assert_eq!("5", print(eval_str_all("(f 5)", &env)));
+        assert_eq!("2", print(eval_str_all("(f 5 6 7)", &env)));
+        assert_eq!("invalid argument: wrong number of args (0) passed to: g",
+                   print(eval_str_all("(def g (fn ([x] x) ([x y] y))) (g)", &env)));
+    }
+
+    #[test]
+    fn fn_body_is_implicit_do() {
+        let env = env();
+
+        assert_eq!("2", print(eval_str_all("((fn* (x) (def y x) (+ x 1)) 1)", &env)));
+    }
+
+    #[test]
+    fn defn_and_defmacro_with_docstrings() {
+        let env = env();
+        eval_str_all("(defn add \"Adds.\" {:added 1} [a b] (+ a b))", &env).unwrap();
+        eval_str_all("(defmacro unless \"Inverted if.\" [c x] (list 'if c nil x))", &env).unwrap();
+
+        assert_eq!("3", print(eval_str_all("(add 1 2)", &env)));
+        assert_eq!("2", print(eval_str_all("(unless nil 2)", &env)));
+
+        let add = eval_str_all("add", &env).unwrap();
+        match *add {
+            AtomType::AFunc(ref fd) => {
+                assert_eq!(Some("Adds."), fd.doc());
+                assert_eq!("{:doc \"Adds.\" :added 1}", format!("{}", c_map(fd.meta.clone())));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn cond_when_and_or() {
+        let env = env();
+
+        assert_eq!("2", print(eval_str_all("(cond nil 1 (= 1 1) 2 :else 3)", &env)));
+        assert_eq!("nil", print(eval_str_all("(cond nil 1)", &env)));
+        assert_eq!("2", print(eval_str_all("(when 1 (def w 1) 2)", &env)));
+        assert_eq!("nil", print(eval_str_all("(when-not 1 (undefined))", &env)));
+        assert_eq!("nil", print(eval_str_all("(and 1 nil (undefined))", &env)));
+        assert_eq!("1", print(eval_str_all("(and)", &env)));
+        assert_eq!("3", print(eval_str_all("(and 1 2 3)", &env)));
+        assert_eq!("2", print(eval_str_all("(or nil 2 (undefined))", &env)));
+        assert_eq!("nil", print(eval_str_all("(or)", &env)));
+    }
+
+    #[test]
+    fn if_let_and_when_let() {
+        let env = env();
+
+        assert_eq!("3", print(eval_str_all("(if-let [[a b] [1 2]] (+ a b) 0)", &env)));
+        assert_eq!("0", print(eval_str_all("(if-let [a nil] a 0)", &env)));
+        assert_eq!("2", print(eval_str_all("(when-let [a 1] (+ a 1))", &env)));
+        assert_eq!("nil", print(eval_str_all("(when-let [a nil] (undefined))", &env)));
+    }
+
+    #[test]
+    fn case_dispatch() {
+        let env = env();
+        eval_str_all("(defn f [x] (case x 1 :one (2 3) :few \"s\" :str :many))", &env).unwrap();
+
+        assert_eq!(":one", print(eval_str_all("(f 1)", &env)));
+        assert_eq!(":few", print(eval_str_all("(f 3)", &env)));
+        assert_eq!(":str", print(eval_str_all("(f \"s\")", &env)));
+        assert_eq!(":many", print(eval_str_all("(f 4)", &env)));
+        assert_eq!("invalid argument: no case matching 2",
+                   print(eval_str_all("(case 2 1 :one)", &env)));
+        assert_eq!("(case* user/x {1 :one} :other)",
+                   print(eval_str_all("(macroexpand-all '(case user/x 1 :one :other))", &env)));
+    }
+
+    #[test]
+    fn control_flow_in_tail_position() {
+        let env = env();
+
+        assert_eq!("3",
+                   print(eval_str_all("(loop (i 0) (cond (= i 3) i :else (recur (inc i))))", &env)));
+        assert_eq!("3",
+                   print(eval_str_all("(loop (i 0) (when i (case i 3 i (recur (inc i)))))", &env)));
+    }
+
+    #[test]
+    fn match_literals_and_bindings() {
+        let env = env();
+        eval_str_all("(defn f [x] (match x 1 :one \"s\" :str nil :nil 'sym :sym n (list :other n)))",
+                     &env)
+            .unwrap();
+
+        assert_eq!(":one", print(eval_str_all("(f 1)", &env)));
+        assert_eq!(":str", print(eval_str_all("(f \"s\")", &env)));
+        assert_eq!(":nil", print(eval_str_all("(f nil)", &env)));
+        assert_eq!(":sym", print(eval_str_all("(f 'sym)", &env)));
+        assert_eq!("(:other 5)", print(eval_str_all("(f 5)", &env)));
+        assert_eq!("invalid argument: no pattern matching 2",
+                   print(eval_str_all("(match 2 1 :one)", &env)));
+    }
+
+    #[test]
+    fn match_shapes() {
+        let env = env();
+        eval_str_all("(defn f [form]
+                        (match form
+                          ('if c a) (list :if1 c a)
+                          ('if c _ _) :if2
+                          (op & args) (list op args)
+                          [x _] x
+                          {:type :point :x x} x
+                          _ :unknown))",
+                     &env)
+            .unwrap();
+
+        assert_eq!("(:if1 c a)", print(eval_str_all("(f '(if c a))", &env)));
+        assert_eq!(":if2", print(eval_str_all("(f '(if c a b))", &env)));
+        assert_eq!("(+ (1 2))", print(eval_str_all("(f '(+ 1 2))", &env)));
+        assert_eq!("1", print(eval_str_all("(f [1 2])", &env)));
+        assert_eq!(":unknown", print(eval_str_all("(f [1 2 3])", &env)));
+        assert_eq!("3", print(eval_str_all("(f {:x 3 :type :point})", &env)));
+        assert_eq!(":unknown", print(eval_str_all("(f {:x 3})", &env)));
+    }
+
+    #[test]
+    fn match_guards() {
+        let env = env();
+        eval_str_all("(defn f [x] (match x [a b] :when (= a b) :same [a b] :different))", &env).unwrap();
+
+        assert_eq!(":same", print(eval_str_all("(f [1 1])", &env)));
+        assert_eq!(":different", print(eval_str_all("(f [1 2])", &env)));
+    }
+
+    #[test]
+    fn set_changes_nearest_binding() {
+        let env = env();
+        eval_str_all("(def counter 0) (defn bump [] (set! counter (inc counter)))", &env).unwrap();
+
+        assert_eq!("2", print(eval_str_all("(bump) (bump) counter", &env)));
+        assert_eq!("(11 2)",
+                   print(eval_str_all("(let [counter 10] (list (set! counter 11) user/counter))", &env)));
+        assert_eq!("undefined symbol: missing", print(eval_str_all("(set! missing 1)", &env)));
+    }
+
+    #[test]
+    fn set_stays_in_its_namespace() {
+        let env = env();
+        eval_str_all("(ns other.lib) (def shared 1) (defn bump [] (set! shared 2))", &env).unwrap();
+        eval_str_all("(ns my.app (:require [other.lib :as o]))", &env).unwrap();
+
+        assert_eq!("invalid operation: can't set! rulsp.core/first from namespace my.app",
+                   print(eval_str_all("(set! first 1)", &env)));
+        assert_eq!("(1 2 2)", print(eval_str_all("(list (first [1 2]) (o/bump) o/shared)", &env)));
+    }
+
+    #[test]
+    fn def_targets_namespace() {
+        let env = env();
+        eval_str_all("(defn f [] (def inner 1) (let [x 2] (def from-let x)))", &env).unwrap();
+
+        assert_eq!("#'user/from-let", print(eval_str_all("(f)", &env)));
+        assert_eq!("(1 2)", print(eval_str_all("(list inner from-let)", &env)));
+    }
+
+    #[test]
+    fn var_watches_see_redefinitions() {
+        let env = env();
+        eval_str_all("(def seen (atom ()))
+                      (def x 1)
+                      (add-watch (var x) :w (fn [k v old new] (reset! seen (list v old new))))
+                      (def x 2)",
+                     &env)
+            .unwrap();
+
+        assert_eq!("(#'user/x 1 2)", print(eval_str_all("@seen", &env)));
+        assert_eq!("2", print(eval_str_all("@(var x)", &env)));
+    }
+
+    #[test]
+    fn defrecord_types() {
+        let env = env();
+        eval_str_all("(defrecord Point [x y]) (def p (->Point 1 2))", &env).unwrap();
+
+        assert_eq!("#Point{:x 1 :y 2}", print(eval_str_all("p", &env)));
+        assert_eq!("(1 2 1 nil)",
+                   print(eval_str_all("(list (Point-x p) (get p :y) (Point? p) (Point? {:x 1}))", &env)));
+        assert_eq!("(1 nil)", print(eval_str_all("(list (= p (->Point 1 2)) (= p {:x 1 :y 2}))", &env)));
+        assert_eq!("(#Point{:x 3 :y 2} #Point{:x 1 :y 2 :z 0} {:y 2})",
+                   print(eval_str_all("(list (assoc p :x 3) (conj p [:z 0]) (dissoc p :x))", &env)));
+        assert_eq!("(#Point{:x 5 :y nil} 3)",
+                   print(eval_str_all("(list (map->Point {:x 5}) (let [{:keys [x y]} p] (+ x y)))", &env)));
+        assert_eq!("(1 :point)",
+                   print(eval_str_all("(list (match p {:y 2 :x x} x) (match p [a] a _ :point))", &env)));
+        assert_eq!("expected: Point, received: Int(1)", print(eval_str_all("(Point-x 1)", &env)));
+        assert_eq!("invalid argument: wrong number of args (1) passed to: ->Point",
+                   print(eval_str_all("(->Point 1)", &env)));
+    }
+
+    #[test]
+    fn multimethods() {
+        let env = env();
+        eval_str_all("(defmulti area (fn [s] (get s :shape)))
+                      (defmethod area :square [s] (* (get s :side) (get s :side)))
+                      (defmethod area :rect [{:keys [w h]}] (* w h))
+                      (defmulti describe \"Describes a number\" (fn [n] (if (= n 0) :zero :other)))
+                      (defmethod describe :zero [n] \"zero\")
+                      (defmethod describe :default [n] \"something else\")",
+                     &env)
+            .unwrap();
+
+        assert_eq!("(9 6)",
+                   print(eval_str_all("(list (area {:shape :square :side 3}) (area {:shape :rect :w 2 :h 3}))",
+                                      &env)));
+        assert_eq!("(\"zero\" \"something else\")",
+                   print(eval_str_all("(list (describe 0) (describe 1))", &env)));
+        assert_eq!("no method in multimethod area for dispatch value: :circle",
+                   print(eval_str_all("(area {:shape :circle})", &env)));
+        assert_eq!("expected: MultiFn, received: Int(1)", print(eval_str_all("(def x 1) (defmethod x 1 [] 1)", &env)));
+    }
+
+    #[test]
+    fn protocols() {
+        let env = env();
+        eval_str_all("(defprotocol Shape \"Things with an area\" (area [this]) (label [this prefix]))
+                      (defrecord Square [side])
+                      (extend-type Square Shape
+                        (area [this] (* (Square-side this) (Square-side this)))
+                        (label [this prefix] (list prefix :square)))
+                      (extend-type Int Shape (area [n] n))
+                      (extend-type nil Shape (area [_] 0))",
+                     &env)
+            .unwrap();
+
+        assert_eq!("(4 5 0 (:a :square))",
+                   print(eval_str_all("(list (area (->Square 2)) (area 5) (area nil) (label (->Square 1) :a))",
+                                      &env)));
+        assert_eq!("(Square Int Str)", print(eval_str_all("(list (type (->Square 1)) (type 1) (type \"\"))", &env)));
+        assert_eq!("no implementation of Shape/area for type: Str", print(eval_str_all("(area \"x\")", &env)));
+        assert_eq!("invalid argument: count is not a method of protocol Shape",
+                   print(eval_str_all("(extend-type Str Shape (count [s] 1))", &env)));
+    }
+
+    #[test]
+    fn binding_dynamic_vars() {
+        let env = env();
+        eval_str_all("(def ^:dynamic *level* 1) (defn level [] *level*)", &env).unwrap();
+
+        assert_eq!("(2 3 1)",
+                   print(eval_str_all("(list (binding [*level* 2] (level))
+                                             (binding [*level* 2] (set! *level* 3) (level))
+                                             (level))",
+                                      &env)));
+        assert_eq!("1", print(eval_str_all("@(var *level*)", &env)));
+        assert_eq!("invalid operation: can't set! user/*level* outside of binding",
+                   print(eval_str_all("(set! *level* 5)", &env)));
+    }
+
+    #[test]
+    fn binding_unwinds_on_error() {
+        let env = env();
+        eval_str_all("(def ^:dynamic *level* 1) (def plain 1)", &env).unwrap();
+
+        assert!(eval_str_all("(binding [*level* 2] (undefined))", &env).is_err());
+        assert_eq!("1", print(eval_str_all("*level*", &env)));
+        assert_eq!("invalid operation: can't dynamically bind non-dynamic var: plain",
+                   print(eval_str_all("(binding [plain 2] plain)", &env)));
+    }
+
+    #[test]
+    fn backquote_splicing_anywhere() {
+        let env = env();
+        eval_str_all("(def xs '(1 2))", &env).unwrap();
+
+        assert_eq!("(1 2 0 1 2)", print(eval_str_all("`(~@xs 0 ~@xs)", &env)));
+        assert_eq!("(0 1 2)", print(eval_str_all("`(0 ~@xs)", &env)));
+        assert_eq!("(0)", print(eval_str_all("`(0 ~@nil)", &env)));
+        assert_eq!("()", print(eval_str_all("`()", &env)));
+    }
+
+    #[test]
+    fn backquote_vectors_and_maps() {
+        let env = env();
+        eval_str_all("(def xs '(1 2))", &env).unwrap();
+
+        assert_eq!("[0 1 2 (1 2)]", print(eval_str_all("`[0 ~@xs ~xs]", &env)));
+        assert_eq!("{:a (1 2) :b [1 2]}", print(eval_str_all("`{:a ~xs :b [~@xs]}", &env)));
+    }
+
+    #[test]
+    fn backquote_nested() {
+        let env = env();
+        eval_str_all("(def x 1) (def y 2)", &env).unwrap();
+
+        assert_eq!("(user/a (backquote (user/b (unquote (user/c 2)))))",
+                   print(eval_str_all("`(a `(b ~(c ~y)))", &env)));
+    }
+
+    #[test]
+    fn backquote_expansion() {
+        let env = env();
+        let form = read_str("`(a ~b ~@c)").unwrap();
+
+        assert_eq!("(rulsp.core/concat (rulsp.core/list (quote user/a) b) c)",
+                   print(macroexpand_all(&form[0], &env)));
+    }
+
+    #[test]
+    fn backquote_auto_gensym() {
+        let env = env();
+        let expanded = eval_str_all("`(a# b# a#)", &env).unwrap();
+        let symbols = expanded.get_list().unwrap();
+
+        assert_eq!(symbols[0], symbols[2]);
+        assert!(symbols[0] != symbols[1]);
+        assert!(symbols[0].get_symbol().unwrap().starts_with("a__"));
+    }
+
+    #[test]
+    fn macro_temporaries_dont_capture() {
+        let env = env();
+        eval_str_all("(defmacro my-or (fn* (a b) `(let ((t# ~a)) (if t# t# ~b))))", &env).unwrap();
+
+        assert_eq!("5", print(eval_str_all("(def t 5) (my-or nil t)", &env)));
+    }
+
+    #[test]
+    fn macro_temporaries_dont_capture_in_vector_let() {
+        let env = env();
+        eval_str_all("(defmacro my-or (fn* (a b) `(let [t# ~a] (if t# t# ~b))))", &env).unwrap();
+
+        assert_eq!("5", print(eval_str_all("(def t 5) (my-or nil t)", &env)));
+    }
+
+    #[test]
+    fn gensyms_are_unique() {
+        let env = env();
+
+        assert_eq!("nil", print(eval_str_all("(= (gensym) (gensym))", &env)));
+        assert!(print(eval_str_all("(gensym \"tmp\")", &env)).starts_with("tmp"));
+    }
+
+    #[test]
+    fn eval_all_skips_shebang() {
+        let env = env();
+
+        assert_eq!("3", print(eval_str_all("#!/usr/bin/env rulsp\n(+ 1 2)", &env)));
+    }
+
+    #[test]
+    fn macroexpand_all_nested() {
+        let env = env();
+        eval_str_all("(defmacro unless (fn* (c x) (list 'if c nil x)))", &env).unwrap();
+
+        let form = read_str("(unless 1 (unless nil 2))").unwrap();
+
+        assert_eq!("(if 1 nil (if nil nil 2))",
+                   print(macroexpand_all(&form[0], &env)));
+    }
 }
@@ -0,0 +1,388 @@
+use std::cell::RefCell;
+use std::cmp;
+use std::rc::{Rc, Weak};
+use fnv::{FnvHashMap, FnvHashSet};
+use data::{AtomVal, AtomType, FnClause};
+use list::Cell;
+use map::{self, AtomMap, Slot};
+use vector;
+use env::{env_references, env_clear, Env, WeakEnv};
+
+// Allocations between automatic collections, at least.
+const COLLECT_THRESHOLD: usize = 10000;
+
+// Envs and atoms are the objects closures can form cycles through. Every one
+// created is tracked here, so `collect` can find the cycles no longer
+// reachable from the rest of the program.
+struct Heap {
+    envs: Vec<WeakEnv>,
+    atoms: Vec<Weak<AtomType>>,
+    // tracked since the last collection
+    allocated: usize,
+    // live objects after the last pruning of dead entries
+    live: usize,
+    collections: usize,
+    freed: usize,
+}
+
+thread_local!(static HEAP: RefCell<Heap> = RefCell::new(Heap {
+    envs: vec![],
+    atoms: vec![],
+    allocated: 0,
+    live: 0,
+    collections: 0,
+    freed: 0,
+}));
+
+#[derive(Debug, PartialEq)]
+pub struct HeapStats {
+    pub envs: usize,
+    pub atoms: usize,
+    pub collections: usize,
+    pub freed: usize,
+}
+
+impl Heap {
+    fn prune(&mut self) {
+        self.envs.retain(|env| env.upgrade().is_some());
+        self.atoms.retain(|atom| atom.upgrade().is_some());
+        self.live = self.envs.len() + self.atoms.len();
+    }
+
+    fn tracked(&mut self) {
+        self.allocated += 1;
+        if self.envs.len() + self.atoms.len() > 2 * self.live + COLLECT_THRESHOLD {
+            self.prune();
+        }
+    }
+}
+
+pub fn track_env(env: &Env) {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.envs.push(Rc::downgrade(env));
+        heap.tracked();
+    })
+}
+
+pub fn track_atom(atom: &AtomVal) {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.atoms.push(Rc::downgrade(atom));
+        heap.tracked();
+    })
+}
+
+pub fn stats() -> HeapStats {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.prune();
+        HeapStats {
+            envs: heap.envs.len(),
+            atoms: heap.atoms.len(),
+            collections: heap.collections,
+            freed: heap.freed,
+        }
+    })
+}
+
+// Object of the heap graph, holding one strong reference to it.
+enum Node {
+    Env(Env),
+    Value(AtomVal),
+    Clauses(Rc<Vec<FnClause>>),
+    // parts of lists, vectors and maps shared between their versions
+    Cell(Rc<Cell>),
+    Items(Rc<Vec<AtomVal>>),
+    VecNode(Rc<vector::Node>),
+    MapNode(Rc<map::Node>),
+}
+
+fn entries(children: &mut Vec<Node>, entries: &[(AtomVal, AtomVal)]) {
+    for &(ref key, ref value) in entries {
+        children.push(Node::Value(key.clone()));
+        children.push(Node::Value(value.clone()));
+    }
+}
+
+fn map_children(children: &mut Vec<Node>, map: &AtomMap) {
+    match map.trie() {
+        Some(root) => children.push(Node::MapNode(root.clone())),
+        None => {
+            for entry in map.iter() {
+                entries(children, &[entry.clone()]);
+            }
+        }
+    }
+}
+
+impl Node {
+    fn id(&self) -> usize {
+        match *self {
+            Node::Env(ref env) => &**env as *const _ as usize,
+            Node::Value(ref value) => &**value as *const _ as usize,
+            Node::Clauses(ref clauses) => &**clauses as *const _ as usize,
+            Node::Cell(ref cell) => &**cell as *const _ as usize,
+            Node::Items(ref items) => &**items as *const _ as usize,
+            Node::VecNode(ref node) => &**node as *const _ as usize,
+            Node::MapNode(ref node) => &**node as *const _ as usize,
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match *self {
+            Node::Env(ref env) => Rc::strong_count(env),
+            Node::Value(ref value) => Rc::strong_count(value),
+            Node::Clauses(ref clauses) => Rc::strong_count(clauses),
+            Node::Cell(ref cell) => Rc::strong_count(cell),
+            Node::Items(ref items) => Rc::strong_count(items),
+            Node::VecNode(ref node) => Rc::strong_count(node),
+            Node::MapNode(ref node) => Rc::strong_count(node),
+        }
+    }
+
+    // Objects this one holds strong references to, None when they can't be
+    // inspected right now, making the node a root.
+    fn children(&self) -> Option<Vec<Node>> {
+        let mut children = vec![];
+        let values = |children: &mut Vec<Node>, values: &[AtomVal]| {
+            children.extend(values.iter().cloned().map(Node::Value))
+        };
+
+        match *self {
+            Node::Env(ref env) => {
+                let (parent, data) = env_references(env)?;
+                children.extend(parent.map(Node::Env));
+                values(&mut children, &data);
+            }
+            Node::Clauses(ref clauses) => {
+                for clause in clauses.iter() {
+                    values(&mut children, &[clause.params.clone(), clause.exp.clone()]);
+                    children.extend(clause.rest.clone().map(Node::Value));
+                }
+            }
+            Node::Cell(ref cell) => {
+                children.push(Node::Value(cell.value().clone()));
+                children.extend(cell.next().cloned().map(Node::Cell));
+            }
+            Node::Items(ref items) => values(&mut children, items),
+            Node::VecNode(ref node) => {
+                match **node {
+                    vector::Node::Branch(ref nodes) => {
+                        children.extend(nodes.iter().cloned().map(Node::VecNode))
+                    }
+                    vector::Node::Leaf(ref items) => values(&mut children, items),
+                }
+            }
+            Node::MapNode(ref node) => {
+                match **node {
+                    map::Node::Branch(_, ref slots) => {
+                        for slot in slots {
+                            match *slot {
+                                Slot::Entry(_, ref entry) => entries(&mut children, &[entry.clone()]),
+                                Slot::Node(ref node) => children.push(Node::MapNode(node.clone())),
+                            }
+                        }
+                    }
+                    map::Node::Collision(_, ref items) => entries(&mut children, items),
+                }
+            }
+            Node::Value(ref value) => {
+                match **value {
+                    AtomType::List(ref list) => {
+                        let (cell, items) = list.parts();
+                        children.extend(cell.cloned().map(Node::Cell));
+                        children.push(Node::Items(items.clone()));
+                    }
+                    AtomType::Vec(ref vec) => {
+                        let (root, tail) = vec.parts();
+                        children.push(Node::VecNode(root.clone()));
+                        children.push(Node::Items(tail.clone()));
+                    }
+                    AtomType::Map(ref map) => map_children(&mut children, map),
+                    AtomType::Set(ref set) => map_children(&mut children, set.as_map()),
+                    AtomType::Record(ref record) => map_children(&mut children, record.values()),
+                    AtomType::AFunc(ref fd) => {
+                        children.push(Node::Env(fd.env.clone()));
+                        children.push(Node::Clauses(fd.clauses.clone()));
+                        map_children(&mut children, &fd.meta);
+                    }
+                    AtomType::Ref(ref cell) => {
+                        values(&mut children, &cell.references()?);
+                    }
+                    AtomType::Var(ref var) => values(&mut children, &var.watches.references()?),
+                    AtomType::LazySeq(ref seq) => values(&mut children, &seq.references()?),
+                    AtomType::Partial(ref func, ref args) => {
+                        children.push(Node::Value(func.clone()));
+                        values(&mut children, args);
+                    }
+                    AtomType::Reduced(ref value) => children.push(Node::Value(value.clone())),
+                    AtomType::Multi(ref multi) => values(&mut children, &multi.references()?),
+                    _ => {}
+                }
+            }
+        }
+
+        Some(children)
+    }
+
+    fn clear(&self) {
+        match *self {
+            Node::Env(ref env) => env_clear(env),
+            Node::Value(ref value) => {
+                if let AtomType::Ref(ref cell) = **value {
+                    cell.clear();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Frees envs and atoms only reachable through reference cycles, returning
+// how many were freed.
+//
+// Starting from the tracked objects, every object reachable from them is
+// visited and the references between visited objects counted. An object with
+// more strong references than that is also used from outside, by the Rust
+// stack, a namespace or a binding. Whatever can't be reached from such an
+// object is garbage, and clearing its envs and atoms breaks the cycles.
+pub fn collect() -> usize {
+    let tracked = HEAP.with(|heap| {
+        let heap = heap.borrow();
+        heap.envs
+            .iter()
+            .filter_map(|env| env.upgrade().map(Node::Env))
+            .chain(heap.atoms.iter().filter_map(|atom| atom.upgrade().map(Node::Value)))
+            .collect::<Vec<_>>()
+    });
+
+    let mut nodes: FnvHashMap<usize, Node> = FnvHashMap::default();
+    let mut internal: FnvHashMap<usize, usize> = FnvHashMap::default();
+    let mut edges: FnvHashMap<usize, Option<Vec<usize>>> = FnvHashMap::default();
+    let mut pending = tracked;
+
+    while let Some(node) = pending.pop() {
+        let id = node.id();
+        if nodes.contains_key(&id) {
+            continue;
+        }
+
+        let children = node.children().map(|children| {
+            let mut ids = Vec::with_capacity(children.len());
+            for child in children {
+                let child_id = child.id();
+                *internal.entry(child_id).or_insert(0) += 1;
+                ids.push(child_id);
+                if !nodes.contains_key(&child_id) {
+                    pending.push(child);
+                }
+            }
+            ids
+        });
+        edges.insert(id, children);
+        nodes.insert(id, node);
+    }
+    // children waiting in `pending` were dropped, `nodes` holds exactly one
+    // reference to each object
+
+    let mut reachable: FnvHashSet<usize> = FnvHashSet::default();
+    let mut pending = nodes.iter()
+        .filter(|&(id, node)| {
+            edges[id].is_none() ||
+            node.strong_count() > 1 + internal.get(id).cloned().unwrap_or(0)
+        })
+        .map(|(id, _)| *id)
+        .collect::<Vec<_>>();
+
+    while let Some(id) = pending.pop() {
+        if !reachable.insert(id) {
+            continue;
+        }
+        if let Some(Some(ref children)) = edges.get(&id) {
+            pending.extend(children.iter().filter(|child| !reachable.contains(child)));
+        }
+    }
+
+    let mut freed = 0;
+    for (id, node) in nodes.iter() {
+        if reachable.contains(id) {
+            continue;
+        }
+        match *node {
+            Node::Env(_) => freed += 1,
+            Node::Value(ref value) => {
+                if let AtomType::Ref(_) = **value {
+                    freed += 1;
+                }
+            }
+            _ => {}
+        }
+        node.clear();
+    }
+    drop(nodes);
+
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.prune();
+        heap.allocated = 0;
+        heap.collections += 1;
+        heap.freed += freed;
+    });
+
+    freed
+}
+
+// Collects when enough was allocated since the last collection. Only called
+// between top level forms.
+pub fn maybe_collect() {
+    let due = HEAP.with(|heap| {
+        let heap = heap.borrow();
+        heap.allocated > cmp::max(COLLECT_THRESHOLD, heap.live)
+    });
+
+    if due {
+        collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{collect, stats};
+    use core;
+    use eval::eval_str_all;
+
+    #[test]
+    fn discarded_closures_are_freed() {
+        let env = core::build();
+        eval_str_all("(defn make [n] (let [inner (fn [] n)] inner))", &env).unwrap();
+        eval_str_all("(def f (make 0)) (def cell (atom nil)) (reset! cell cell)", &env).unwrap();
+
+        collect();
+        let before = stats();
+
+        for _ in 0..200 {
+            eval_str_all("(def f (make 1)) (f)", &env).unwrap();
+            eval_str_all("(def cell (atom nil)) (reset! cell cell)", &env).unwrap();
+        }
+        collect();
+        let after = stats();
+
+        assert_eq!(before.envs, after.envs);
+        assert_eq!(before.atoms, after.atoms);
+        assert!(after.freed >= before.freed + 400);
+    }
+
+    #[test]
+    fn reachable_closures_survive() {
+        let env = core::build();
+        eval_str_all("(defn make [n] (let [inner (fn [] n)] inner))", &env).unwrap();
+        eval_str_all("(def keep (atom nil)) (reset! keep (make 5))", &env).unwrap();
+        let held = eval_str_all("(make 6)", &env).unwrap();
+
+        collect();
+
+        assert_eq!(eval_str_all("(@keep)", &env).unwrap(), eval_str_all("5", &env).unwrap());
+        assert_eq!(format!("{}", held.apply(&[]).unwrap()), "6");
+    }
+}
@@ -0,0 +1,202 @@
+use std::borrow::Cow;
+use fnv::FnvHashSet;
+use rustyline::Helper;
+use rustyline::completion::Completer;
+use rustyline::hint::Hinter;
+use rustyline::highlight::Highlighter;
+use lexer::{lex_spans, Token};
+use env::{Env, env_bindings};
+use eval::SPECIAL_FORMS;
+
+const RESET: &'static str = "\x1b[0m";
+const NUMBER: &'static str = "\x1b[33m";
+const STRING: &'static str = "\x1b[32m";
+const COMMENT: &'static str = "\x1b[90m";
+const SPECIAL_FORM: &'static str = "\x1b[1;35m";
+const BUILTIN: &'static str = "\x1b[34m";
+const MATCHING_PAREN: &'static str = "\x1b[1;36m";
+
+pub struct ReplHelper {
+    builtins: FnvHashSet<String>,
+}
+
+impl ReplHelper {
+    pub fn new(env: &Env) -> ReplHelper {
+        let mut helper = ReplHelper { builtins: FnvHashSet::default() };
+        helper.refresh(env);
+        helper
+    }
+
+    // Names bound in `env`, called before reading each line so definitions,
+    // namespace switches and resets show up.
+    pub fn refresh(&mut self, env: &Env) {
+        self.builtins = env_bindings(env)
+            .into_iter()
+            .map(|(key, _)| key.to_string())
+            .collect();
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(&self, _line: &str, pos: usize) -> ::rustyline::Result<(usize, Vec<String>)> {
+        Ok((pos, vec![]))
+    }
+}
+
+impl Hinter for ReplHelper {
+    fn hint(&self, _line: &str, _pos: usize) -> Option<String> {
+        None
+    }
+}
+
+impl Highlighter for ReplHelper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        match highlight(line, pos, &self.builtins) {
+            Some(highlighted) => Cow::Owned(highlighted),
+            None => Cow::Borrowed(line),
+        }
+    }
+
+    // Cursor movement changes which parens are matched, so always redraw.
+    fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+        true
+    }
+}
+
+impl Helper for ReplHelper {}
+
+// Index of the paren token matching the one at `index`.
+fn matching_paren(tokens: &[(Token, usize, usize)], index: usize) -> Option<usize> {
+    let mut depth = 0;
+    if tokens[index].0.is_open() {
+        for (i, &(ref token, _, _)) in tokens.iter().enumerate().skip(index) {
+            if token.is_open() {
+                depth += 1;
+            } else if token.is_close() {
+                depth -= 1;
+            } else {
+                continue;
+            }
+            if depth == 0 {
+                return Some(i);
+            }
+        }
+    } else if tokens[index].0.is_close() {
+        for i in (0..index + 1).rev() {
+            if tokens[i].0.is_close() {
+                depth += 1;
+            } else if tokens[i].0.is_open() {
+                depth -= 1;
+            } else {
+                continue;
+            }
+            if depth == 0 {
+                return Some(i);
+            }
+        }
+    }
+
+    None
+}
+
+// Paren tokens to emphasize: the one under the cursor, or right before it,
+// together with its pair.
+fn cursor_parens(tokens: &[(Token, usize, usize)], pos: usize) -> Option<(usize, usize)> {
+    let is_paren = |&(ref token, _, _): &(Token, usize, usize)| token.is_open() || token.is_close();
+
+    let under_cursor = tokens.iter().position(|t| t.1 == pos && is_paren(t));
+    let before_cursor = tokens.iter().position(|t| t.2 == pos && is_paren(t));
+
+    under_cursor.or(before_cursor)
+        .and_then(|index| matching_paren(tokens, index).map(|pair| (index, pair)))
+}
+
+pub fn highlight(line: &str, pos: usize, builtins: &FnvHashSet<String>) -> Option<String> {
+    let tokens = match lex_spans(line) {
+        Ok(tokens) => tokens,
+        Err(_) => return None,
+    };
+    let parens = cursor_parens(&tokens, pos);
+
+    let mut output = String::with_capacity(line.len() * 2);
+    for (index, &(ref token, start, end)) in tokens.iter().enumerate() {
+        let text = &line[start..end];
+        let color = match *token {
+            Token::Int(_) => Some(NUMBER),
+            Token::Str(_) => Some(STRING),
+            Token::Comment => Some(COMMENT),
+            Token::Identifier(ref name) if SPECIAL_FORMS.contains(&name.as_str()) => {
+                Some(SPECIAL_FORM)
+            }
+            Token::Identifier(ref name) if builtins.contains(name) => Some(BUILTIN),
+            ref paren if paren.is_open() || paren.is_close() => {
+                match parens {
+                    Some((a, b)) if a == index || b == index => Some(MATCHING_PAREN),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+
+        match color {
+            Some(color) => {
+                output.push_str(color);
+                output.push_str(text);
+                output.push_str(RESET);
+            }
+            None => output.push_str(text),
+        }
+    }
+
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use fnv::FnvHashSet;
+    use rustyline::highlight::Highlighter;
+    use core;
+    use eval::eval_str;
+    use super::{highlight, ReplHelper};
+
+    fn builtins() -> FnvHashSet<String> {
+        let mut builtins = FnvHashSet::default();
+        builtins.insert("+".to_string());
+        builtins
+    }
+
+    #[test]
+    fn highlight_tokens() {
+        assert_eq!(highlight("(if 1 (+ x \"s\")) ; c", 100, &builtins()).unwrap(),
+                   "(\x1b[1;35mif\x1b[0m \x1b[33m1\x1b[0m (\x1b[34m+\x1b[0m x \
+                    \x1b[32m\"s\"\x1b[0m)) \x1b[90m; c\x1b[0m");
+    }
+
+    #[test]
+    fn highlight_matching_paren() {
+        let line = "(a (b) c)";
+
+        assert_eq!(highlight(line, 0, &builtins()).unwrap(),
+                   "\x1b[1;36m(\x1b[0ma (b) c\x1b[1;36m)\x1b[0m");
+        assert_eq!(highlight(line, 6, &builtins()).unwrap(),
+                   "(a \x1b[1;36m(\x1b[0mb\x1b[1;36m)\x1b[0m c)");
+    }
+
+    #[test]
+    fn highlight_names_defined_after_start() {
+        let env = core::build();
+        let mut helper = ReplHelper::new(&env);
+        eval_str("(def answer 42)", &env).unwrap();
+        assert_eq!(helper.highlight("answer", 0), "answer");
+
+        helper.refresh(&env);
+        assert_eq!(helper.highlight("answer", 0), "\x1b[34manswer\x1b[0m");
+    }
+
+    #[test]
+    fn highlight_unbalanced_paren() {
+        assert_eq!(highlight("((a)", 0, &builtins()).unwrap(), "((a)");
+    }
+}
@@ -0,0 +1,195 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+use data::{AtomVal, AtomType, AtomError, seq_iter, uncons};
+
+// Elements realized when printing a lazy sequence, the rest shows as `...`.
+pub const PRINT_LIMIT: usize = 100;
+
+enum State {
+    // function and args called to produce the sequence
+    Thunk(AtomVal, Vec<AtomVal>),
+    // first element and the rest of the sequence
+    Cons(AtomVal, AtomVal),
+    Empty,
+}
+
+// Sequence computed the first time its elements are needed, by calling the
+// thunk with its args. The thunk returns any collection, string or nil, whose
+// first element and rest are remembered so it is only called once.
+pub struct LazySeq {
+    state: RefCell<State>,
+}
+
+impl LazySeq {
+    pub fn new(func: AtomVal, args: Vec<AtomVal>) -> LazySeq {
+        LazySeq { state: RefCell::new(State::Thunk(func, args)) }
+    }
+
+    // Already realized sequence of `first` followed by `rest`.
+    pub fn cons(first: AtomVal, rest: AtomVal) -> LazySeq {
+        LazySeq { state: RefCell::new(State::Cons(first, rest)) }
+    }
+
+    pub fn is_realized(&self) -> bool {
+        match *self.state.borrow() {
+            State::Thunk(..) => false,
+            _ => true,
+        }
+    }
+
+    // First element and the rest, None when the sequence is empty.
+    pub fn step(&self) -> Result<Option<(AtomVal, AtomVal)>, AtomError> {
+        let state = mem::replace(&mut *self.state.borrow_mut(), State::Empty);
+        let thunk = match state {
+            State::Thunk(func, args) => (func, args),
+            State::Cons(first, rest) => {
+                *self.state.borrow_mut() = State::Cons(first.clone(), rest.clone());
+                return Ok(Some((first, rest)));
+            }
+            State::Empty => return Ok(None),
+        };
+
+        let step = match thunk.0.apply(&thunk.1).and_then(|seq| uncons(&seq)) {
+            Ok(step) => step,
+            Err(err) => {
+                *self.state.borrow_mut() = State::Thunk(thunk.0, thunk.1);
+                return Err(err);
+            }
+        };
+        if let Some((ref first, ref rest)) = step {
+            *self.state.borrow_mut() = State::Cons(first.clone(), rest.clone());
+        }
+
+        Ok(step)
+    }
+
+    // Realizes the whole sequence.
+    pub fn to_vec(&self) -> Result<Vec<AtomVal>, AtomError> {
+        let mut items = vec![];
+        let mut step = self.step()?;
+        while let Some((first, rest)) = step {
+            items.push(first);
+            step = uncons(&rest)?;
+        }
+
+        Ok(items)
+    }
+
+    // Up to `limit` elements, and whether there are more.
+    pub fn prefix(&self, limit: usize) -> Result<(Vec<AtomVal>, bool), AtomError> {
+        let mut items = vec![];
+        let mut step = self.step()?;
+        while let Some((first, rest)) = step {
+            if items.len() == limit {
+                return Ok((items, true));
+            }
+            items.push(first);
+            step = uncons(&rest)?;
+        }
+
+        Ok((items, false))
+    }
+
+    // Like prefix, but only walks the elements realized already. An
+    // unrealized rest counts as more elements, so printing never calls a thunk.
+    pub fn realized_prefix(&self, limit: usize) -> (Vec<AtomVal>, bool) {
+        let mut items = vec![];
+        let mut step = self.realized_step();
+        loop {
+            let (first, rest) = match step {
+                Some(Some(cons)) => cons,
+                Some(None) => return (items, false),
+                None => return (items, true),
+            };
+            if items.len() == limit {
+                return (items, true);
+            }
+            items.push(first);
+
+            step = match *rest {
+                AtomType::LazySeq(ref seq) => seq.realized_step(),
+                _ => {
+                    for item in seq_iter(&rest) {
+                        let item = match item {
+                            Ok(item) => item,
+                            Err(_) => break,
+                        };
+                        if items.len() == limit {
+                            return (items, true);
+                        }
+                        items.push(item);
+                    }
+                    return (items, false);
+                }
+            };
+        }
+    }
+
+    // First element and the rest without calling the thunk, None while unrealized.
+    fn realized_step(&self) -> Option<Option<(AtomVal, AtomVal)>> {
+        match *self.state.borrow() {
+            State::Thunk(..) => None,
+            State::Cons(ref first, ref rest) => Some(Some((first.clone(), rest.clone()))),
+            State::Empty => Some(None),
+        }
+    }
+
+    // Thunk and its args or the realized elements, for walking the heap.
+    // None while the sequence is being realized.
+    pub fn references(&self) -> Option<Vec<AtomVal>> {
+        match *self.state.try_borrow().ok()? {
+            State::Thunk(ref func, ref args) => {
+                let mut references = args.clone();
+                references.push(func.clone());
+                Some(references)
+            }
+            State::Cons(ref first, ref rest) => Some(vec![first.clone(), rest.clone()]),
+            State::Empty => Some(vec![]),
+        }
+    }
+}
+
+// Realized rest of a long sequence is dropped one by one instead of
+// recursively, so it can't overflow the stack.
+impl Drop for LazySeq {
+    fn drop(&mut self) {
+        let mut next = match mem::replace(self.state.get_mut(), State::Empty) {
+            State::Cons(_, rest) => rest,
+            _ => return,
+        };
+        loop {
+            next = match Rc::try_unwrap(next) {
+                Ok(AtomType::LazySeq(mut seq)) => {
+                    match mem::replace(seq.state.get_mut(), State::Empty) {
+                        State::Cons(_, rest) => rest,
+                        _ => break,
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+}
+
+// Equal when both realize to equal elements.
+impl PartialEq for LazySeq {
+    fn eq(&self, other: &LazySeq) -> bool {
+        match (self.to_vec(), other.to_vec()) {
+            (Ok(items), Ok(other)) => items == other,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Debug for LazySeq {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self.state.borrow() {
+            State::Thunk(..) => write!(f, "LazySeq(<unrealized>)"),
+            State::Cons(ref first, _) => write!(f, "LazySeq({:?} ..)", first),
+            State::Empty => write!(f, "LazySeq()"),
+        }
+    }
+}
+
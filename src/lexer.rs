@@ -40,17 +40,25 @@ impl StdError for LexError {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Oparen,
     Cparen,
+    Obracket,
+    Cbracket,
+    Obrace,
+    Cbrace,
+    Oset,
     Identifier(String),
     Int(i64),
+    Str(String),
     Whitespace,
     Apostrophe,
     Backquote,
     Unquote,
     UnquoteSplicing,
+    Deref,
+    Meta,
     Comment,
 }
 
@@ -62,6 +70,20 @@ impl Token {
             _ => false,
         }
     }
+
+    pub fn is_open(&self) -> bool {
+        match self {
+            &Token::Oparen | &Token::Obracket | &Token::Obrace | &Token::Oset => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_close(&self) -> bool {
+        match self {
+            &Token::Cparen | &Token::Cbracket | &Token::Cbrace => true,
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Display for Token {
@@ -92,57 +114,80 @@ pub fn format_tokens(tokens: &Vec<Token>) -> String {
 }
 
 lazy_static! {
-    static ref TOKEN_MATCHES: Regex = Regex::new(r"(?x)
+    static ref TOKEN_MATCHES: Regex = Regex::new(r#"(?x)
         (?P<whitespace>^\s+)                 |
-        (?P<comment>;(.*)\n)                 |
+        (?P<comment>^;.*\n?)                 |
+        (?P<string>^"(\\.|[^"\\])*")          |
         (?P<oparen>^\()                      |
         (?P<cparen>^\))                      |
         (?P<obracket>^\[)                    |
         (?P<cbracket>^\])                    |
+        (?P<obrace>^\{)                      |
+        (?P<oset>^\#\{)                      |
+        (?P<cbrace>^\})                      |
         (?P<integer>^[0-9]+)                 |
         (?P<apostrophe>^')                   |
         (?P<backquote>^`)                    |
         (?P<unquote_splicing>^~@)            |
         (?P<unquote>^~)                      |
+        (?P<deref>^@)                        |
+        (?P<meta>^\^)                        |
         (?P<identifier>^([^\s\(\)\[\]\{\}]+))
-    ").unwrap();
+    "#).unwrap();
 }
 
 pub fn lex(content: &str) -> Result<Vec<Token>, LexError> {
-    let mut tokens: Vec<Token> = vec![];
+    Result::Ok(lex_spans(content)?.into_iter().map(|(token, _, _)| token).collect())
+}
+
+// Tokens with their start and end byte offsets in `content`.
+pub fn lex_spans(content: &str) -> Result<Vec<(Token, usize, usize)>, LexError> {
+    let mut tokens: Vec<(Token, usize, usize)> = vec![];
     let mut code = content;
+    let mut offset = 0;
+
+    // `#!` on the first line lets scripts be executed directly.
+    if content.starts_with("#!") {
+        offset = content.find('\n').map(|index| index + 1).unwrap_or(content.len());
+        tokens.push((Token::Comment, 0, offset));
+        code = &content[offset..];
+    }
 
     while code != "" {
         let found_token = lex_single_token(code);
 
         match found_token {
             Ok((name, token)) => {
-                // let code_token = &code[0..token.len()];
-                // if token.cmp(&code_token.to_string()) != Ordering::Equal {
-                //     return Result::Err(LexError::InvalidToken(code_token.to_string(),
-                //                                               token.to_string()));
-                // }
                 code = &code[token.len()..code.len()];
+                let start = offset;
+                offset += token.len();
 
-                tokens.push(match name.as_str() {
+                let token = match name.as_str() {
                     "whitespace" => Token::Whitespace,
                     "oparen" => Token::Oparen,
                     "cparen" => Token::Cparen,
-                    "obracket" => Token::Oparen,
-                    "cbrakcet" => Token::Cparen,
+                    "obracket" => Token::Obracket,
+                    "cbracket" => Token::Cbracket,
+                    "obrace" => Token::Obrace,
+                    "cbrace" => Token::Cbrace,
+                    "oset" => Token::Oset,
                     "identifier" => Token::Identifier(token),
                     "integer" => Token::Int(token.parse::<i64>().unwrap()),
+                    "string" => Token::Str(unescape(&token[1..token.len() - 1])),
                     "apostrophe" => Token::Apostrophe,
                     "backquote" => Token::Backquote,
                     "unquote" => Token::Unquote,
                     "unquote_splicing" => Token::UnquoteSplicing,
+                    "deref" => Token::Deref,
+                    "meta" => Token::Meta,
                     "comment" => Token::Comment,
                     _ => {
                         println!("NAME: {:?}", name);
                         unreachable!()
                     }
 
-                });
+                };
+                tokens.push((token, start, offset));
             }
             Err(err) => return Result::Err(err),
         }
@@ -151,6 +196,27 @@ pub fn lex(content: &str) -> Result<Vec<Token>, LexError> {
     Result::Ok(tokens)
 }
 
+fn unescape(str: &str) -> String {
+    let mut output = String::with_capacity(str.len());
+    let mut chars = str.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => output.push('\n'),
+            Some('t') => output.push('\t'),
+            Some(other) => output.push(other),
+            None => {}
+        }
+    }
+
+    output
+}
+
 fn lex_single_token(str: &str) -> Result<(String, String), LexError> {
     for cap in TOKEN_MATCHES.captures_iter(str) {
         for (name, matched) in cap.iter_named() {
@@ -0,0 +1,204 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::rc::Rc;
+use data::AtomVal;
+
+// Element consed onto the front of a list.
+pub struct Cell {
+    value: AtomVal,
+    next: Option<Rc<Cell>>,
+}
+
+impl Cell {
+    pub fn value(&self) -> &AtomVal {
+        &self.value
+    }
+
+    pub fn next(&self) -> Option<&Rc<Cell>> {
+        self.next.as_ref()
+    }
+}
+
+// Dropping a long chain of cells one by one instead of recursively, so it
+// can't overflow the stack.
+impl Drop for Cell {
+    fn drop(&mut self) {
+        let mut next = self.next.take();
+        while let Some(cell) = next {
+            match Rc::try_unwrap(cell) {
+                Ok(mut cell) => next = cell.next.take(),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+// Persistent list. Elements consed onto it are linked cells, followed by the
+// elements it was created with, `start..` of a shared vector. `cons`, `first`
+// and `rest` share the structure of the list and don't copy it.
+#[derive(Clone)]
+pub struct AtomList {
+    cells: Option<Rc<Cell>>,
+    items: Rc<Vec<AtomVal>>,
+    start: usize,
+    len: usize,
+}
+
+impl AtomList {
+    pub fn new() -> AtomList {
+        AtomList::from_vec(vec![])
+    }
+
+    pub fn from_vec(items: Vec<AtomVal>) -> AtomList {
+        AtomList {
+            cells: None,
+            len: items.len(),
+            items: Rc::new(items),
+            start: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn first(&self) -> Option<&AtomVal> {
+        match self.cells {
+            Some(ref cell) => Some(&cell.value),
+            None => self.items.get(self.start),
+        }
+    }
+
+    // List without its first element, empty for an empty list.
+    pub fn rest(&self) -> AtomList {
+        match self.cells {
+            Some(ref cell) => {
+                AtomList {
+                    cells: cell.next.clone(),
+                    items: self.items.clone(),
+                    start: self.start,
+                    len: self.len - 1,
+                }
+            }
+            None if self.is_empty() => self.clone(),
+            None => {
+                AtomList {
+                    cells: None,
+                    items: self.items.clone(),
+                    start: self.start + 1,
+                    len: self.len - 1,
+                }
+            }
+        }
+    }
+
+    pub fn cons(&self, value: AtomVal) -> AtomList {
+        AtomList {
+            cells: Some(Rc::new(Cell {
+                value: value,
+                next: self.cells.clone(),
+            })),
+            items: self.items.clone(),
+            start: self.start,
+            len: self.len + 1,
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&AtomVal> {
+        self.iter().nth(index)
+    }
+
+    pub fn iter<'a>(&'a self) -> Iter<'a> {
+        Iter {
+            cell: self.cells.as_ref().map(|cell| &**cell),
+            items: self.items[self.start..].iter(),
+        }
+    }
+
+    // First cell and the shared vector, for walking the heap.
+    pub fn parts(&self) -> (Option<&Rc<Cell>>, &Rc<Vec<AtomVal>>) {
+        (self.cells.as_ref(), &self.items)
+    }
+
+    // Elements as a slice, only copied when some were consed onto the list.
+    pub fn as_slice<'a>(&'a self) -> Cow<'a, [AtomVal]> {
+        match self.cells {
+            None => Cow::Borrowed(&self.items[self.start..]),
+            Some(_) => Cow::Owned(self.iter().cloned().collect()),
+        }
+    }
+}
+
+pub struct Iter<'a> {
+    cell: Option<&'a Cell>,
+    items: ::std::slice::Iter<'a, AtomVal>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a AtomVal;
+
+    fn next(&mut self) -> Option<&'a AtomVal> {
+        match self.cell {
+            Some(cell) => {
+                self.cell = cell.next.as_ref().map(|cell| &**cell);
+                Some(&cell.value)
+            }
+            None => self.items.next(),
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a AtomList {
+    type Item = &'a AtomVal;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+impl PartialEq for AtomList {
+    fn eq(&self, other: &AtomList) -> bool {
+        self.len == other.len && self.iter().zip(other.iter()).all(|(a, b)| a == b)
+    }
+}
+
+impl fmt::Debug for AtomList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AtomList;
+    use data::c_int;
+
+    #[test]
+    fn cons_and_rest_share_the_tail() {
+        let base = AtomList::from_vec(vec![c_int(2), c_int(3)]);
+        let list = base.cons(c_int(1));
+        let other = base.rest().cons(c_int(4));
+
+        assert_eq!(list.len(), 3);
+        assert_eq!(list.first(), Some(&c_int(1)));
+        assert_eq!(list.rest(), base);
+        assert_eq!(list.get(2), Some(&c_int(3)));
+        assert_eq!(&*other.as_slice(), &[c_int(4), c_int(3)][..]);
+        assert_eq!(list.rest().rest().rest().rest(), AtomList::new());
+    }
+
+    #[test]
+    fn long_lists_drop() {
+        let mut list = AtomList::new();
+        for n in 0..1000000 {
+            list = list.cons(c_int(n));
+        }
+
+        assert_eq!(list.len(), 1000000);
+    }
+}
@@ -0,0 +1,226 @@
+use std::cell::RefCell;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+use data::{AtomVal, AtomType, AtomRet, AtomError, c_nil, c_list, c_string, c_symbol};
+use env::{env_get, env_set, env_root, Env};
+use eval::eval_str_all;
+use namespace;
+
+// Files currently being loaded, outermost first, used to detect cycles.
+thread_local!(static LOADING: RefCell<Vec<String>> = RefCell::new(vec![]));
+
+pub fn read_file(path: &str) -> Result<String, AtomError> {
+    let mut content = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut content))
+        .map_err(|err| AtomError::InvalidArgument(format!("{}: {}", path, err)))?;
+
+    Ok(content)
+}
+
+fn canonical(path: &str) -> String {
+    fs::canonicalize(path)
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| path.to_string())
+}
+
+// Evaluates all forms of the file at `path` in `env`. A `ns` form in the file
+// only lasts until the end of it.
+pub fn load_file(path: &str, env: &Env) -> AtomRet {
+    let content = read_file(path)?;
+    let key = canonical(path);
+
+    let cycle = LOADING.with(|loading| {
+        let loading = loading.borrow();
+        loading.iter().position(|loaded| *loaded == key).map(|index| {
+            let mut chain = loading[index..].to_vec();
+            chain.push(key.clone());
+            chain.join(" -> ")
+        })
+    });
+    if let Some(chain) = cycle {
+        return Err(AtomError::InvalidOperation(format!("circular load: {}", chain)));
+    }
+
+    let previous_ns = namespace::current_name(env);
+    LOADING.with(|loading| loading.borrow_mut().push(key));
+    let result = eval_str_all(&content, env);
+    LOADING.with(|loading| loading.borrow_mut().pop());
+    if let Some(previous_ns) = previous_ns {
+        namespace::switch(env, &previous_ns)?;
+    }
+
+    result.map_err(|err| AtomError::InFile(path.to_string(), Box::new(err)))
+}
+
+// Finds `foo.bar` as `foo/bar.clrs` in one of the *load-path* directories.
+fn resolve(name: &str, env: &Env) -> Result<String, AtomError> {
+    let relative = format!("{}.clrs", name.replace('.', "/"));
+    let load_path = env_get(env, &c_symbol("*load-path*")).unwrap_or_else(c_nil);
+
+    if let AtomType::List(ref dirs) = *load_path {
+        for dir in dirs.iter() {
+            let path = Path::new(dir.get_str()?).join(&relative);
+            if path.is_file() {
+                return Ok(path.to_string_lossy().into_owned());
+            }
+        }
+    }
+
+    Err(AtomError::InvalidArgument(format!("could not find {} ({}) in *load-path*", name, relative)))
+}
+
+// Loads module `name` unless it's in *loaded-libs* or already a namespace.
+// The spec can be a symbol or `[name :as alias]`, which adds the alias to
+// env's namespace.
+pub fn require(spec: &AtomVal, env: &Env) -> AtomRet {
+    let (name, alias) = match **spec {
+        AtomType::Vec(_) | AtomType::List(_) => {
+            let items = spec.get_list()?;
+            let alias = items.iter()
+                .position(|item| item.is_keyword("as"))
+                .and_then(|index| items.get(index + 1))
+                .cloned();
+            (items.get(0).cloned().unwrap_or_else(c_nil), alias)
+        }
+        _ => (spec.clone(), None),
+    };
+
+    let root = env_root(env);
+    let loaded_key = c_symbol("*loaded-libs*");
+    let is_loaded = match env_get(&root, &loaded_key) {
+        Some(loaded) => loaded.get_list()?.contains(&name),
+        None => false,
+    } || namespace::exists(env, name.get_symbol()?);
+
+    if !is_loaded {
+        let path = resolve(name.get_symbol()?, &root)?;
+        load_file(&path, env)?;
+
+        let mut loaded = match env_get(&root, &loaded_key) {
+            Some(loaded) => loaded.get_list()?.to_vec(),
+            None => vec![],
+        };
+        loaded.push(name.clone());
+        env_set(&root, &loaded_key, c_list(&loaded))?;
+    }
+
+    if let Some(alias) = alias {
+        namespace::add_alias(env, alias.get_symbol()?, name.get_symbol()?)?;
+    }
+
+    Ok(c_nil())
+}
+
+// Puts `dirs` in front of the current *load-path*.
+pub fn prepend_load_path(dirs: &[String], env: &Env) -> Result<(), AtomError> {
+    let key = c_symbol("*load-path*");
+    let mut load_path = dirs.iter().map(|dir| c_string(dir)).collect::<Vec<_>>();
+    if let Some(current) = env_get(env, &key) {
+        load_path.extend(current.get_list()?.iter().cloned());
+    }
+
+    env_set(&env_root(env), &key, c_list(&load_path))
+}
+
+#[allow(unused_must_use)]
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::prelude::*;
+    use std::path::PathBuf;
+    use core;
+    use data::{AtomError, c_int};
+    use eval::eval_str_all;
+    use super::prepend_load_path;
+
+    fn tmp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rulsp-load-{}", name));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(dir: &PathBuf, name: &str, content: &str) -> String {
+        let path = dir.join(name);
+        File::create(&path).unwrap().write_all(content.as_bytes()).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn load_evaluates_all_forms() {
+        let dir = tmp_dir("all-forms");
+        let path = write(&dir, "a.clrs", "(def a 1)\n(def b (+ a 1))");
+        let env = core::build();
+
+        eval_str_all(&format!("(load \"{}\")", path), &env).unwrap();
+
+        assert_eq!(eval_str_all("(+ a b)", &env).unwrap(), c_int(3));
+    }
+
+    #[test]
+    fn load_error_names_file() {
+        let dir = tmp_dir("error");
+        let path = write(&dir, "broken.clrs", "(def a 1)\n(undefined-fn)");
+        let env = core::build();
+
+        match eval_str_all(&format!("(load \"{}\")", path), &env) {
+            Err(AtomError::InFile(ref file, ref err)) => {
+                assert_eq!(*file, path);
+                assert_eq!(**err, AtomError::UndefinedSymbol("undefined-fn".to_string()));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn require_loads_once() {
+        let dir = tmp_dir("once");
+        fs::create_dir_all(dir.join("my")).unwrap();
+        write(&dir, "my/counter.clrs", "(def loads (+ loads 1))");
+        let env = core::build();
+        prepend_load_path(&[dir.to_string_lossy().into_owned()], &env);
+
+        eval_str_all("(def loads 0) (require 'my.counter) (require 'my.counter)", &env).unwrap();
+
+        assert_eq!(eval_str_all("loads", &env).unwrap(), c_int(1));
+    }
+
+    #[test]
+    fn require_detects_cycles() {
+        let dir = tmp_dir("cycle");
+        write(&dir, "ping.clrs", "(require 'pong)");
+        write(&dir, "pong.clrs", "(require 'ping)");
+        let env = core::build();
+        prepend_load_path(&[dir.to_string_lossy().into_owned()], &env);
+
+        let err = format!("{}", eval_str_all("(require 'ping)", &env).unwrap_err());
+
+        assert!(err.contains("circular load"), err);
+    }
+
+    #[test]
+    fn require_with_alias() {
+        let dir = tmp_dir("alias");
+        fs::create_dir_all(dir.join("my")).unwrap();
+        write(&dir, "my/math.clrs", "(ns my.math) (def twice (fn* (x) (* 2 x)))");
+        let env = core::build();
+        prepend_load_path(&[dir.to_string_lossy().into_owned()], &env);
+
+        eval_str_all("(require '[my.math :as m])", &env).unwrap();
+
+        assert_eq!(eval_str_all("(m/twice 4)", &env).unwrap(), c_int(8));
+        assert_eq!(eval_str_all("(my.math/twice 1)", &env).unwrap(), c_int(2));
+        assert!(eval_str_all("twice", &env).is_err());
+    }
+
+    #[test]
+    fn require_missing_module() {
+        let env = core::build();
+
+        assert!(eval_str_all("(require 'does.not.exist)", &env).is_err());
+    }
+}
@@ -15,34 +15,236 @@ extern crate log;
 extern crate env_logger;
 
 mod data;
+mod list;
+mod map;
+mod vector;
+mod lazy;
+mod record;
+mod multi;
 mod lexer;
 mod parser;
 mod env;
 mod eval;
 mod core;
-
+mod load;
+mod namespace;
+mod dynamic;
+mod gc;
+mod highlight;
+
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::process;
+use std::time::Instant;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
-use env::{Env};
-use eval::eval_str;
+use env::{Env, env_get, env_set, env_bindings};
+use highlight::ReplHelper;
+use load::{load_file, prepend_load_path};
+use namespace::{current_env, current_name};
+use data::{AtomRet, AtomError, AtomType, c_symbol, c_string, c_list, realize_printed};
+use eval::{eval_str, eval_str_all, read_str, macroexpand_all};
+
+const USAGE: &'static str = "\
+usage: rulsp [--core <file>] <command>
+
+       --core <file>                  load the prelude from file instead of the
+                                      bundled one, also read from RULSP_CORE
+
+       rulsp repl                     start the repl
+       rulsp run <file> [args...]     run a script, - reads it from stdin
+       rulsp -e <expr> [args...]      evaluate expr and print its value
+       rulsp - [args...]              run a script read from stdin
+       rulsp <file> [args...]         same as run, for #! scripts
+       rulsp <number>                 run the counting benchmark";
+
+const REPL_HELP: &'static str = "\
+:env [prefix]  list bindings, optionally only those starting with prefix
+:doc sym       describe the value bound to sym
+:type expr     evaluate expr and print the type of its value
+:expand form   fully macroexpand form without evaluating it
+:load file     evaluate all forms from file
+:reset         start over with a fresh environment
+:time expr     evaluate expr and print how long it took
+:quit          exit the repl";
+
+enum MetaResult {
+    Continue,
+    Quit,
+}
+
+fn print_result(result: AtomRet) {
+    match result.and_then(|value| realize_printed(&value).map(|_| value)) {
+        Ok(result) => println!(">> {}", result),
+        Err(err) => println!(">> {:?}", err),
+    };
+}
+
+fn read_stdin() -> Result<String, AtomError> {
+    let mut content = String::new();
+    io::stdin()
+        .read_to_string(&mut content)
+        .map(|_| content)
+        .map_err(|err| AtomError::InvalidArgument(format!("stdin: {}", err)))
+}
+
+fn bind_command_line_args(args: &[String], env: &Env) -> Result<(), AtomError> {
+    let args = args.iter().map(|arg| c_string(arg)).collect::<Vec<_>>();
+    env_set(env, &c_symbol("*command-line-args*"), c_list(&args))
+}
+
+// Lets scripts require modules next to them and from RULSP_PATH.
+fn script_load_path(path: &str, env: &Env) -> Result<(), AtomError> {
+    let mut dirs = vec![];
+    if path != "-" {
+        match Path::new(path).parent() {
+            Some(dir) if dir != Path::new("") => dirs.push(dir.to_string_lossy().into_owned()),
+            _ => {}
+        }
+    }
+    if let Ok(paths) = std::env::var("RULSP_PATH") {
+        dirs.extend(paths.split(':').filter(|dir| !dir.is_empty()).map(|dir| dir.to_string()));
+    }
+
+    prepend_load_path(&dirs, env)
+}
+
+fn run(path: &str, args: &[String], env: &Env) -> AtomRet {
+    bind_command_line_args(args, env)?;
+    script_load_path(path, env)?;
+
+    if path == "-" {
+        eval_str_all(&read_stdin()?, env)
+    } else {
+        load_file(path, env)
+    }
+}
+
+fn run_expression(expr: &str, args: &[String], env: &Env) -> AtomRet {
+    bind_command_line_args(args, env)?;
+    let result = eval_str_all(expr, env)?;
+    realize_printed(&result)?;
+    if *result != AtomType::Nil {
+        println!("{}", result);
+    }
 
-#[allow(dead_code)]
-fn repl(env: &Env) {
-    let mut rl = Editor::<()>::new();
+    Ok(result)
+}
+
+fn exit_code(result: AtomRet) -> i32 {
+    match result {
+        Ok(_) => 0,
+        Err(err) => {
+            let _ = writeln!(io::stderr(), "error: {}", err);
+            1
+        }
+    }
+}
+
+fn describe(name: &str, env: &Env) -> String {
+    let value = match env_get(env, &c_symbol(name)) {
+        Some(value) => value,
+        None => return format!("{} is not defined", name),
+    };
+
+    match *value {
+        AtomType::AFunc(ref data) => {
+            let mut lines = vec![name.to_string()];
+            lines.extend(data.clauses.iter().map(|clause| format!("  {}", clause.params)));
+            lines.push(format!("  {}", value.type_name()));
+            if let Some(doc) = data.doc() {
+                lines.push(format!("  {}", doc));
+            }
+            lines.join("\n")
+        }
+        AtomType::Func(_) |
+        AtomType::Partial(..) => format!("{}\n  builtin", name),
+        _ => format!("{}\n  {} {}", name, value.type_name(), value),
+    }
+}
+
+fn meta_command(line: &str, env: &mut Env, prelude: Option<&str>) -> MetaResult {
+    let line = line.trim();
+    let (command, arg) = match line.find(char::is_whitespace) {
+        Some(index) => (&line[..index], line[index..].trim()),
+        None => (line, ""),
+    };
+    let current = current_env(env);
+
+    match command {
+        ":env" => {
+            for (key, value) in env_bindings(&current) {
+                if key.starts_with(arg) {
+                    println!("{} {}", key, value);
+                }
+            }
+        }
+        ":doc" => println!("{}", describe(arg, &current)),
+        ":type" => {
+            match eval_str_all(arg, &current) {
+                Ok(value) => println!(">> {}", value.type_name()),
+                err => print_result(err),
+            }
+        }
+        ":expand" => {
+            let expanded = read_str(arg).and_then(|forms| match forms.first() {
+                Some(form) => macroexpand_all(form, &current),
+                None => Err(AtomError::InvalidArgument(":expand needs a form".to_string())),
+            });
+            print_result(expanded);
+        }
+        ":load" => print_result(load_file(arg, &current)),
+        ":reset" => {
+            match core::build_with_prelude(prelude) {
+                Ok(new_env) => {
+                    *env = new_env;
+                    println!(">> environment reset");
+                }
+                Err(err) => println!(">> {:?}", err),
+            }
+        }
+        ":time" => {
+            let start = Instant::now();
+            let result = eval_str_all(arg, &current);
+            let elapsed = start.elapsed();
+
+            print_result(result);
+            println!("elapsed: {:.3}ms",
+                     elapsed.as_secs() as f64 * 1000.0 + elapsed.subsec_nanos() as f64 / 1_000_000.0);
+        }
+        ":quit" | ":q" => return MetaResult::Quit,
+        ":help" => println!("{}", REPL_HELP),
+        _ => println!("unknown command {}, try :help", command),
+    };
+
+    MetaResult::Continue
+}
+
+fn repl(mut env: Env, prelude: Option<&str>) {
+    let mut rl = Editor::<ReplHelper>::new();
+    rl.set_helper(Some(ReplHelper::new(&env)));
     if let Err(_) = rl.load_history("history.txt") {
         println!("No previous history.");
     }
 
     loop {
-        let readline = rl.readline(">> ");
+        if let Some(helper) = rl.helper_mut() {
+            helper.refresh(&current_env(&env));
+        }
+        let prompt = format!("{}>> ", current_name(&env).unwrap_or_default());
+        let readline = rl.readline(&prompt);
         match readline {
             Ok(line) => {
-                rl.add_history_entry(&line);
-                let result = eval_str(line.as_str(), env);
-                match result {
-                    Ok(result) => println!(">> {}", result),
-                    Err(err) => println!(">> {:?}", err)
-                };
+                rl.add_history_entry(line.as_str());
+                if line.trim_left().starts_with(':') {
+                    match meta_command(&line, &mut env, prelude) {
+                        MetaResult::Continue => continue,
+                        MetaResult::Quit => break,
+                    }
+                }
+
+                print_result(eval_str(line.as_str(), &current_env(&env)));
             }
             Err(ReadlineError::Interrupted) => {
                 println!("CTRL-C");
@@ -69,20 +271,45 @@ fn count(n: String, env: &Env) {
 
 fn main() {
     env_logger::init().unwrap();
-    let env = core::build();
-
-    match std::env::args().nth(1) {
-        Some(value) => {
-            if "repl" == value  {
-                repl(&env);
-            } else {
-                count(value, &env)
-            }
-        },
-        None => {
-            println!("Pass repl or any number as a first param to count")
+    let mut args = std::env::args().skip(1).collect::<Vec<String>>();
+
+    let prelude = if args.len() > 1 && args[0] == "--core" {
+        Some(args.drain(..2).nth(1).unwrap())
+    } else {
+        std::env::var("RULSP_CORE").ok()
+    };
+    let prelude = prelude.as_ref().map(|path| path.as_str());
+
+    let env = match core::build_with_prelude(prelude) {
+        Ok(env) => env,
+        Err(err) => {
+            let _ = writeln!(io::stderr(), "error: {}", err);
+            process::exit(1);
+        }
+    };
+
+    let code = match args.first().map(|arg| arg.as_str()) {
+        Some("repl") => {
+            let _ = bind_command_line_args(&[], &env);
+            repl(env, prelude);
+            0
+        }
+        Some("run") if args.len() > 1 => exit_code(run(&args[1], &args[2..], &env)),
+        Some("-e") if args.len() > 1 => exit_code(run_expression(&args[1], &args[2..], &env)),
+        Some("-") => exit_code(run("-", &args[1..], &env)),
+        Some(value) if value.parse::<u64>().is_ok() => {
+            count(value.to_string(), &env);
+            0
+        }
+        // `#!/usr/bin/env rulsp` scripts are invoked with their path
+        Some(path) if !path.starts_with('-') => exit_code(run(path, &args[1..], &env)),
+        _ => {
+            let _ = writeln!(io::stderr(), "{}", USAGE);
+            2
         }
     };
+
+    process::exit(code);
 }
 
 #[allow(unused_must_use)]
@@ -92,6 +319,7 @@ mod tests {
     use ::core;
     use ::env::{Env, env_get};
     use ::data::{AtomError, c_int, c_symbol, c_list, c_nil};
+    use super::{describe, run_expression};
 
     fn env() -> Env {
         core::build()
@@ -188,7 +416,9 @@ mod tests {
     fn eval_map() {
         let env = env();
 
-        assert_eq!(eval_str("(map (fn* (x) (+ x 1)) '(1 2))", &env).unwrap(), c_list(&[c_int(2), c_int(3)]));
+        let mapped = eval_str("(map (fn* (x) (+ x 1)) '(1 2))", &env).unwrap();
+
+        assert_eq!(&*mapped.get_list().unwrap(), &[c_int(2), c_int(3)][..]);
     }
 
     #[test]
@@ -205,6 +435,44 @@ mod tests {
         assert_eq!(eval_str("(loop (x 2 acc 0) (if (= x 1) acc (recur (- x 1) (+ acc x))))", &env).unwrap(), c_int(2));
     }
 
+    #[test]
+    fn repl_describe() {
+        let env = env();
+        eval_str("(def foo 1)", &env);
+
+        assert_eq!(describe("foo", &env), "foo\n  Int 1");
+        assert_eq!(describe("second", &env), "second\n  (coll)\n  AFunc");
+        assert_eq!(describe("missing", &env), "missing is not defined");
+
+        eval_str("(defn bar \"Does bar.\" ([] 0) ([x] x))", &env);
+        assert_eq!(describe("bar", &env), "bar\n  []\n  [x]\n  AFunc\n  Does bar.");
+    }
+
+    #[test]
+    fn script_command_line_args() {
+        let env = env();
+        let args = vec!["a".to_string(), "b".to_string()];
+
+        assert_eq!(run_expression("(count *command-line-args*)", &args, &env).unwrap(),
+                   c_int(2));
+        assert!(run_expression("(undefined-fn)", &args, &env).is_err());
+    }
+
+    #[test]
+    fn run_expression_doesnt_echo_printed_values() {
+        let env = env();
+
+        // nil results aren't printed, so only println's own output is shown
+        assert_eq!(run_expression("(println \"hi\" \"there\")", &[], &env).unwrap(), c_nil());
+    }
+
+    #[test]
+    fn run_expression_fails_when_printing_lazy_seq_fails() {
+        let env = env();
+
+        assert!(run_expression("(map (fn [x] (undefined-fn x)) [1 2])", &[], &env).is_err());
+    }
+
 
     use test::Bencher;
 
@@ -0,0 +1,622 @@
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::rc::Rc;
+use std::slice;
+use fnv::FnvHasher;
+use data::{AtomVal, AtomType, c_map, c_nil};
+
+// Maps up to this size are kept as a vector in insertion order, bigger ones
+// become a trie.
+const ARRAY_MAP_SIZE: usize = 8;
+const BITS: u32 = 5;
+const MASK: u32 = (1 << BITS) - 1;
+
+pub type Entry = (AtomVal, AtomVal);
+
+// Hash consistent with equality: lists, vectors, maps and sets hash their
+// contents, maps and sets regardless of order. References hash by identity.
+pub fn hash_value(value: &AtomVal) -> u32 {
+    let mut hasher = FnvHasher::default();
+    write_value(&mut hasher, value);
+    let hash = hasher.finish();
+    (hash ^ (hash >> 32)) as u32
+}
+
+fn write_value<H: Hasher>(hasher: &mut H, value: &AtomVal) {
+    value.type_name().hash(hasher);
+    match **value {
+        AtomType::Int(num) => num.hash(hasher),
+        AtomType::Str(ref str) => str.hash(hasher),
+        AtomType::Symbol(ref str) |
+        AtomType::Keyword(ref str) => str.hash(hasher),
+        AtomType::List(ref list) => {
+            list.len().hash(hasher);
+            for item in list.iter() {
+                write_value(hasher, item);
+            }
+        }
+        AtomType::Vec(ref vec) => {
+            vec.len().hash(hasher);
+            for item in vec.iter() {
+                write_value(hasher, item);
+            }
+        }
+        AtomType::Map(ref map) => {
+            let sum = map.iter().fold(0u32, |sum, &(ref key, ref value)| {
+                sum.wrapping_add(hash_value(key) ^ hash_value(value).rotate_left(16))
+            });
+            sum.hash(hasher);
+        }
+        AtomType::Set(ref set) => {
+            let sum = set.iter().fold(0u32, |sum, item| sum.wrapping_add(hash_value(item)));
+            sum.hash(hasher);
+        }
+        AtomType::Record(ref record) => {
+            record.name().hash(hasher);
+            write_value(hasher, &c_map(record.values().clone()));
+        }
+        AtomType::LazySeq(ref seq) => {
+            let items = seq.to_vec().unwrap_or_default();
+            items.len().hash(hasher);
+            for item in &items {
+                write_value(hasher, item);
+            }
+        }
+        AtomType::Ref(_) |
+        AtomType::Var(_) => (&**value as *const AtomType as usize).hash(hasher),
+        _ => {}
+    }
+}
+
+#[derive(Clone)]
+pub enum Slot {
+    Entry(u32, Entry),
+    Node(Rc<Node>),
+}
+
+// Hash array mapped trie. Each level picks a slot by the next 5 bits of the
+// hash, `bitmap` telling which of the 32 slots are present. Keys with the
+// same hash end up together in a collision node.
+#[derive(Clone)]
+pub enum Node {
+    Branch(u32, Vec<Slot>),
+    Collision(u32, Vec<Entry>),
+}
+
+fn bit(hash: u32, shift: u32) -> u32 {
+    1 << ((hash >> shift) & MASK)
+}
+
+// Node holding two entries with different keys.
+fn merge(hash_a: u32, entry_a: Entry, hash_b: u32, entry_b: Entry, shift: u32) -> Node {
+    if hash_a == hash_b {
+        return Node::Collision(hash_a, vec![entry_a, entry_b]);
+    }
+
+    let (bit_a, bit_b) = (bit(hash_a, shift), bit(hash_b, shift));
+    if bit_a == bit_b {
+        let child = merge(hash_a, entry_a, hash_b, entry_b, shift + BITS);
+        Node::Branch(bit_a, vec![Slot::Node(Rc::new(child))])
+    } else if bit_a < bit_b {
+        Node::Branch(bit_a | bit_b, vec![Slot::Entry(hash_a, entry_a), Slot::Entry(hash_b, entry_b)])
+    } else {
+        Node::Branch(bit_a | bit_b, vec![Slot::Entry(hash_b, entry_b), Slot::Entry(hash_a, entry_a)])
+    }
+}
+
+impl Node {
+    fn get(&self, hash: u32, key: &AtomVal, shift: u32) -> Option<&Entry> {
+        match *self {
+            Node::Branch(bitmap, ref slots) => {
+                let bit = bit(hash, shift);
+                if bitmap & bit == 0 {
+                    return None;
+                }
+
+                match slots[(bitmap & (bit - 1)).count_ones() as usize] {
+                    Slot::Entry(entry_hash, ref entry) => {
+                        if entry_hash == hash && entry.0 == *key { Some(entry) } else { None }
+                    }
+                    Slot::Node(ref node) => node.get(hash, key, shift + BITS),
+                }
+            }
+            Node::Collision(node_hash, ref entries) => {
+                if node_hash == hash { entries.iter().find(|entry| entry.0 == *key) } else { None }
+            }
+        }
+    }
+
+    // Returns whether the key is a new one.
+    fn insert(&mut self, hash: u32, key: AtomVal, value: AtomVal, shift: u32) -> bool {
+        if let Node::Collision(node_hash, _) = *self {
+            if node_hash != hash {
+                // pushed one level down, under a branch telling the hashes apart
+                let collision = mem::replace(self, Node::Branch(0, vec![]));
+                *self = Node::Branch(bit(node_hash, shift), vec![Slot::Node(Rc::new(collision))]);
+            }
+        }
+
+        match *self {
+            Node::Branch(ref mut bitmap, ref mut slots) => {
+                let bit = bit(hash, shift);
+                let index = (*bitmap & (bit - 1)).count_ones() as usize;
+                if *bitmap & bit == 0 {
+                    slots.insert(index, Slot::Entry(hash, (key, value)));
+                    *bitmap |= bit;
+                    return true;
+                }
+
+                let merged = match slots[index] {
+                    Slot::Node(ref mut node) => {
+                        return Rc::make_mut(node).insert(hash, key, value, shift + BITS);
+                    }
+                    Slot::Entry(entry_hash, ref mut entry) => {
+                        if entry_hash == hash && entry.0 == key {
+                            entry.1 = value;
+                            return false;
+                        }
+                        let existing = mem::replace(entry, (c_nil(), c_nil()));
+                        merge(entry_hash, existing, hash, (key, value), shift + BITS)
+                    }
+                };
+                slots[index] = Slot::Node(Rc::new(merged));
+                true
+            }
+            Node::Collision(_, ref mut entries) => {
+                match entries.iter().position(|entry| entry.0 == key) {
+                    Some(index) => {
+                        entries[index].1 = value;
+                        false
+                    }
+                    None => {
+                        entries.push((key, value));
+                        true
+                    }
+                }
+            }
+        }
+    }
+
+    // Returns whether the key was there.
+    fn remove(&mut self, hash: u32, key: &AtomVal, shift: u32) -> bool {
+        match *self {
+            Node::Branch(ref mut bitmap, ref mut slots) => {
+                let bit = bit(hash, shift);
+                if *bitmap & bit == 0 {
+                    return false;
+                }
+
+                let index = (*bitmap & (bit - 1)).count_ones() as usize;
+                let (removed, replacement) = match slots[index] {
+                    Slot::Entry(entry_hash, ref entry) => {
+                        (entry_hash == hash && entry.0 == *key, None)
+                    }
+                    Slot::Node(ref mut node) => {
+                        let node = Rc::make_mut(node);
+                        if !node.remove(hash, key, shift + BITS) {
+                            return false;
+                        }
+                        // a node left with a single entry is replaced by it
+                        (true, node.single_entry())
+                    }
+                };
+
+                match (removed, replacement) {
+                    (false, _) => false,
+                    (true, Some(slot)) => {
+                        slots[index] = slot;
+                        true
+                    }
+                    (true, None) => {
+                        if let Slot::Entry(..) = slots[index] {
+                            slots.remove(index);
+                            *bitmap &= !bit;
+                        }
+                        true
+                    }
+                }
+            }
+            Node::Collision(node_hash, ref mut entries) => {
+                if node_hash != hash {
+                    return false;
+                }
+                match entries.iter().position(|entry| entry.0 == *key) {
+                    Some(index) => {
+                        entries.remove(index);
+                        true
+                    }
+                    None => false,
+                }
+            }
+        }
+    }
+
+    fn single_entry(&self) -> Option<Slot> {
+        match *self {
+            Node::Branch(_, ref slots) if slots.len() == 1 => {
+                match slots[0] {
+                    Slot::Entry(..) => Some(slots[0].clone()),
+                    Slot::Node(_) => None,
+                }
+            }
+            Node::Collision(hash, ref entries) if entries.len() == 1 => {
+                Some(Slot::Entry(hash, entries[0].clone()))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn trie_of(entries: Vec<Entry>) -> Repr {
+    let len = entries.len();
+    let mut root = Node::Branch(0, vec![]);
+    for (key, value) in entries {
+        root.insert(hash_value(&key), key, value, 0);
+    }
+    Repr::Trie(Rc::new(root), len)
+}
+
+#[derive(Clone)]
+enum Repr {
+    Array(Vec<Entry>),
+    Trie(Rc<Node>, usize),
+}
+
+// Persistent map. Small maps keep entries in insertion order, bigger ones
+// live in a hash trie sharing unchanged nodes between versions. Methods
+// taking `&mut self` change the map in place wherever its nodes aren't
+// shared, which makes building a map from scratch as cheap as with a
+// mutable one. Maps are equal to any map with the same entries regardless
+// of their order.
+#[derive(Clone)]
+pub struct AtomMap {
+    repr: Repr,
+}
+
+impl AtomMap {
+    pub fn new() -> AtomMap {
+        AtomMap { repr: Repr::Array(vec![]) }
+    }
+
+    // Map from `[k1 v1 k2 v2 ...]`, None when a value is missing.
+    pub fn from_flat(items: &[AtomVal]) -> Option<AtomMap> {
+        if items.len() % 2 == 1 {
+            return None;
+        }
+
+        let mut map = AtomMap::new();
+        for pair in items.chunks(2) {
+            map.insert(pair[0].clone(), pair[1].clone());
+        }
+
+        Some(map)
+    }
+
+    pub fn get(&self, key: &AtomVal) -> Option<&AtomVal> {
+        match self.repr {
+            Repr::Array(ref entries) => entries.iter().find(|entry| entry.0 == *key),
+            Repr::Trie(ref root, _) => root.get(hash_value(key), key, 0),
+        }.map(|entry| &entry.1)
+    }
+
+    pub fn contains_key(&self, key: &AtomVal) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn insert(&mut self, key: AtomVal, value: AtomVal) {
+        let entries = match self.repr {
+            Repr::Array(ref mut entries) => {
+                match entries.iter().position(|entry| entry.0 == key) {
+                    Some(index) => entries[index].1 = value,
+                    None => entries.push((key, value)),
+                }
+                if entries.len() <= ARRAY_MAP_SIZE {
+                    return;
+                }
+                mem::replace(entries, vec![])
+            }
+            Repr::Trie(ref mut root, ref mut len) => {
+                if Rc::make_mut(root).insert(hash_value(&key), key, value, 0) {
+                    *len += 1;
+                }
+                return;
+            }
+        };
+
+        self.repr = trie_of(entries);
+    }
+
+    // Same map kept in a trie whatever its size, so keys are found by their
+    // hash instead of comparing them one by one. Used for tables that are
+    // only looked up, like the constants of `case`.
+    pub fn hashed(&self) -> AtomMap {
+        match self.repr {
+            Repr::Array(ref entries) => AtomMap { repr: trie_of(entries.clone()) },
+            Repr::Trie(..) => self.clone(),
+        }
+    }
+
+    pub fn remove(&mut self, key: &AtomVal) {
+        match self.repr {
+            Repr::Array(ref mut entries) => entries.retain(|entry| entry.0 != *key),
+            Repr::Trie(ref mut root, ref mut len) => {
+                if Rc::make_mut(root).remove(hash_value(key), key, 0) {
+                    *len -= 1;
+                }
+            }
+        }
+    }
+
+    // New map with `key` set to `value`, sharing the rest with this one.
+    pub fn assoc(&self, key: AtomVal, value: AtomVal) -> AtomMap {
+        let mut map = self.clone();
+        map.insert(key, value);
+        map
+    }
+
+    pub fn dissoc(&self, key: &AtomVal) -> AtomMap {
+        let mut map = self.clone();
+        map.remove(key);
+        map
+    }
+
+    // Root of the trie, None while the entries are kept in the map itself.
+    pub fn trie(&self) -> Option<&Rc<Node>> {
+        match self.repr {
+            Repr::Array(_) => None,
+            Repr::Trie(ref root, _) => Some(root),
+        }
+    }
+
+    pub fn iter<'a>(&'a self) -> Iter<'a> {
+        match self.repr {
+            Repr::Array(ref entries) => Iter(IterRepr::Array(entries.iter())),
+            Repr::Trie(ref root, _) => Iter(IterRepr::Trie(vec![Frame::of(root)])),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self.repr {
+            Repr::Array(ref entries) => entries.len(),
+            Repr::Trie(_, len) => len,
+        }
+    }
+}
+
+enum Frame<'a> {
+    Slots(slice::Iter<'a, Slot>),
+    Entries(slice::Iter<'a, Entry>),
+}
+
+impl<'a> Frame<'a> {
+    fn of(node: &'a Node) -> Frame<'a> {
+        match *node {
+            Node::Branch(_, ref slots) => Frame::Slots(slots.iter()),
+            Node::Collision(_, ref entries) => Frame::Entries(entries.iter()),
+        }
+    }
+}
+
+enum IterRepr<'a> {
+    Array(slice::Iter<'a, Entry>),
+    Trie(Vec<Frame<'a>>),
+}
+
+pub struct Iter<'a>(IterRepr<'a>);
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a Entry;
+
+    fn next(&mut self) -> Option<&'a Entry> {
+        let stack = match self.0 {
+            IterRepr::Array(ref mut entries) => return entries.next(),
+            IterRepr::Trie(ref mut stack) => stack,
+        };
+
+        loop {
+            let next = match stack.last_mut() {
+                None => return None,
+                Some(&mut Frame::Entries(ref mut entries)) => {
+                    match entries.next() {
+                        Some(entry) => return Some(entry),
+                        None => None,
+                    }
+                }
+                Some(&mut Frame::Slots(ref mut slots)) => {
+                    match slots.next() {
+                        Some(&Slot::Entry(_, ref entry)) => return Some(entry),
+                        Some(&Slot::Node(ref node)) => Some(Frame::of(node)),
+                        None => None,
+                    }
+                }
+            };
+
+            match next {
+                Some(frame) => stack.push(frame),
+                None => {
+                    stack.pop();
+                }
+            }
+        }
+    }
+}
+
+impl PartialEq for AtomMap {
+    fn eq(&self, other: &AtomMap) -> bool {
+        self.len() == other.len() &&
+        self.iter().all(|&(ref key, ref value)| other.get(key) == Some(value))
+    }
+}
+
+impl fmt::Debug for AtomMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter().map(|&(ref key, ref value)| (key, value))).finish()
+    }
+}
+
+// Persistent set, a map of its elements to nil.
+#[derive(Clone, PartialEq)]
+pub struct AtomSet {
+    map: AtomMap,
+}
+
+impl AtomSet {
+    pub fn new() -> AtomSet {
+        AtomSet { map: AtomMap::new() }
+    }
+
+    pub fn from_items(items: &[AtomVal]) -> AtomSet {
+        let mut set = AtomSet::new();
+        for item in items {
+            set.insert(item.clone());
+        }
+        set
+    }
+
+    pub fn as_map(&self) -> &AtomMap {
+        &self.map
+    }
+
+    pub fn contains(&self, item: &AtomVal) -> bool {
+        self.map.contains_key(item)
+    }
+
+    pub fn insert(&mut self, item: AtomVal) {
+        self.map.insert(item, c_nil());
+    }
+
+    pub fn remove(&mut self, item: &AtomVal) {
+        self.map.remove(item);
+    }
+
+    pub fn conj(&self, item: AtomVal) -> AtomSet {
+        let mut set = self.clone();
+        set.insert(item);
+        set
+    }
+
+    pub fn iter<'a>(&'a self) -> ::std::iter::Map<Iter<'a>, fn(&'a Entry) -> &'a AtomVal> {
+        fn key<'a>(entry: &'a Entry) -> &'a AtomVal {
+            &entry.0
+        }
+        self.map.iter().map(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+}
+
+impl fmt::Debug for AtomSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AtomMap, AtomSet};
+    use data::{c_int, c_keyword, c_list, c_vec, AtomVal};
+    use test::Bencher;
+
+    const SIZE: i64 = 100000;
+    const UPDATES: i64 = 100;
+
+    fn filled() -> AtomMap {
+        let mut map = AtomMap::new();
+        for n in 0..SIZE {
+            map.insert(c_int(n), c_int(n * 2));
+        }
+        map
+    }
+
+    #[test]
+    fn insert_get_and_remove() {
+        let map = filled();
+        let mut smaller = map.dissoc(&c_int(7));
+        for n in 0..SIZE / 2 {
+            smaller.remove(&c_int(n * 2));
+        }
+
+        assert_eq!(map.len(), SIZE as usize);
+        assert_eq!(map.get(&c_int(777)), Some(&c_int(1554)));
+        assert_eq!(map.iter().count(), SIZE as usize);
+        assert_eq!(smaller.len(), SIZE as usize / 2 - 1);
+        assert_eq!(smaller.get(&c_int(7)), None);
+        assert_eq!(smaller.get(&c_int(9)), Some(&c_int(18)));
+        assert_eq!(map.get(&c_int(7)), Some(&c_int(14)));
+        assert_eq!(map.assoc(c_int(1), c_int(0)).get(&c_int(1)), Some(&c_int(0)));
+    }
+
+    #[test]
+    fn hashed_small_map() {
+        let small = AtomMap::from_flat(&[c_keyword("a"), c_int(1), c_keyword("b"), c_int(2)]).unwrap();
+        let hashed = small.hashed();
+
+        assert!(small.trie().is_none());
+        assert!(hashed.trie().is_some());
+        assert_eq!(hashed, small);
+        assert_eq!(hashed.get(&c_keyword("b")), Some(&c_int(2)));
+        assert_eq!(hashed.get(&c_keyword("c")), None);
+    }
+
+    #[test]
+    fn equal_regardless_of_order() {
+        let small = AtomMap::from_flat(&[c_keyword("a"), c_int(1), c_keyword("b"), c_int(2)]).unwrap();
+        let big = filled();
+        let mut reversed = AtomMap::new();
+        for n in (0..SIZE).rev() {
+            reversed.insert(c_int(n), c_int(n * 2));
+        }
+
+        assert_eq!(small, AtomMap::from_flat(&[c_keyword("b"), c_int(2), c_keyword("a"), c_int(1)]).unwrap());
+        assert_eq!(big, reversed);
+        assert!(big != big.assoc(c_int(0), c_int(1)));
+        assert_eq!(AtomSet::from_items(&[c_list(&[c_int(1)]), c_vec(&[c_int(1)])]).len(), 2);
+    }
+
+    fn pairs() -> Vec<(AtomVal, AtomVal)> {
+        (0..SIZE).map(|n| (c_int(n), c_int(n * 2))).collect()
+    }
+
+    #[bench]
+    fn bench_assoc_persistent(b: &mut Bencher) {
+        let map = filled();
+        b.iter(|| {
+            let mut updated = map.clone();
+            for n in 0..UPDATES {
+                updated = updated.assoc(c_int(n * 997), c_int(-1));
+            }
+            updated
+        });
+    }
+
+    #[bench]
+    fn bench_assoc_copying(b: &mut Bencher) {
+        let map = pairs();
+        b.iter(|| {
+            let mut updated = map.clone();
+            for n in 0..UPDATES {
+                updated = updated.clone();
+                let key = c_int(n * 997);
+                if let Some(entry) = updated.iter_mut().find(|entry| entry.0 == key) {
+                    entry.1 = c_int(-1);
+                }
+            }
+            updated
+        });
+    }
+
+    #[bench]
+    fn bench_build_in_place(b: &mut Bencher) {
+        let pairs = pairs();
+        b.iter(|| {
+            let mut map = AtomMap::new();
+            for &(ref key, ref value) in &pairs {
+                map.insert(key.clone(), value.clone());
+            }
+            map
+        });
+    }
+}
@@ -0,0 +1,92 @@
+use std::cell::RefCell;
+use std::ptr;
+use data::{AtomVal, AtomType, AtomRet, AtomError, AtomMap, c_func, c_keyword, c_nil, c_symbol};
+
+// Function created by `defmulti`, calling the method registered for the
+// value its dispatch function returns for the args, or the :default one.
+// The methods of a protocol dispatch on the type of their first arg.
+#[derive(Debug)]
+pub struct AtomMulti {
+    pub name: String,
+    dispatch: AtomVal,
+    // protocol the method belongs to
+    protocol: Option<String>,
+    methods: RefCell<AtomMap>,
+}
+
+impl AtomMulti {
+    pub fn new(name: &str, dispatch: AtomVal) -> AtomMulti {
+        AtomMulti {
+            name: name.to_string(),
+            dispatch,
+            protocol: None,
+            methods: RefCell::new(AtomMap::new()),
+        }
+    }
+
+    pub fn protocol_method(name: &str, protocol: &str) -> AtomMulti {
+        AtomMulti {
+            protocol: Some(protocol.to_string()),
+            ..AtomMulti::new(name, c_func(type_of))
+        }
+    }
+
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_ref().map(|protocol| protocol.as_str())
+    }
+
+    pub fn add_method(&self, value: AtomVal, method: AtomVal) {
+        self.methods.borrow_mut().insert(value, method);
+    }
+
+    pub fn call(&self, args: &[AtomVal]) -> AtomRet {
+        let value = self.dispatch.apply(args)?;
+        let method = {
+            let methods = self.methods.borrow();
+            methods.get(&value).or_else(|| methods.get(&c_keyword("default"))).cloned()
+        };
+
+        match (method, self.protocol()) {
+            (Some(method), _) => method.apply(args),
+            (None, Some(protocol)) => {
+                Err(AtomError::NoMethod(format!("no implementation of {}/{} for type: {}",
+                                                protocol,
+                                                self.name,
+                                                value)))
+            }
+            (None, None) => {
+                Err(AtomError::NoMethod(format!("no method in multimethod {} for dispatch value: {}",
+                                                self.name,
+                                                value)))
+            }
+        }
+    }
+
+    // Dispatch function and the methods, None while the methods are being
+    // changed.
+    pub fn references(&self) -> Option<Vec<AtomVal>> {
+        let methods = self.methods.try_borrow().ok()?;
+        let mut references = vec![self.dispatch.clone()];
+        for &(ref value, ref method) in methods.iter() {
+            references.push(value.clone());
+            references.push(method.clone());
+        }
+        Some(references)
+    }
+}
+
+// Multimethods are only equal to themselves.
+impl PartialEq for AtomMulti {
+    fn eq(&self, other: &AtomMulti) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+// [value] - symbol naming the type of value, as protocols dispatch on it:
+// the name of a record type, or of the kind of value like Int or Vec
+pub fn type_of(args: &[AtomVal]) -> AtomRet {
+    match *args.first().cloned().unwrap_or_else(c_nil) {
+        AtomType::Record(ref record) => Ok(c_symbol(record.name())),
+        ref value => Ok(c_symbol(value.type_name())),
+    }
+}
@@ -0,0 +1,275 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use fnv::{FnvHashMap, FnvHashSet};
+use data::{AtomVal, AtomType, AtomRet, AtomError, c_symbol, c_var};
+use env::{c_ns_env, env_defining_namespace, env_get, env_get_own, env_set, env_namespace, env_namespace_env, env_is_namespace,
+          Env};
+
+pub const CORE_NS: &'static str = "rulsp.core";
+pub const USER_NS: &'static str = "user";
+
+pub struct Namespace {
+    pub name: String,
+    registry: Registry,
+    // alias -> namespace name
+    aliases: RefCell<FnvHashMap<String, String>>,
+    private: RefCell<FnvHashSet<String>>,
+    // name -> var returned by def
+    vars: RefCell<FnvHashMap<String, AtomVal>>,
+}
+
+impl PartialEq for Namespace {
+    fn eq(&self, other: &Namespace) -> bool {
+        self.name == other.name
+    }
+}
+
+pub struct Namespaces {
+    envs: FnvHashMap<String, Env>,
+    current: String,
+}
+
+pub type Registry = Rc<RefCell<Namespaces>>;
+
+pub fn c_registry() -> Registry {
+    Rc::new(RefCell::new(Namespaces {
+        envs: FnvHashMap::default(),
+        current: CORE_NS.to_string(),
+    }))
+}
+
+fn registry(env: &Env) -> Result<Registry, AtomError> {
+    env_namespace(env)
+        .map(|ns| ns.registry.clone())
+        .ok_or_else(|| AtomError::InvalidOperation("env doesn't belong to a namespace".to_string()))
+}
+
+// Namespace env called `name`, created on first use. Every namespace sees
+// the core one as its parent.
+pub fn find_or_create(registry: &Registry, name: &str) -> Env {
+    if let Some(env) = registry.borrow().envs.get(name) {
+        return env.clone();
+    }
+
+    let parent = registry.borrow().envs.get(CORE_NS).cloned();
+    let ns = Namespace {
+        name: name.to_string(),
+        registry: registry.clone(),
+        aliases: RefCell::new(FnvHashMap::default()),
+        private: RefCell::new(FnvHashSet::default()),
+        vars: RefCell::new(FnvHashMap::default()),
+    };
+    let env = c_ns_env(parent, Rc::new(ns));
+    registry.borrow_mut().envs.insert(name.to_string(), env.clone());
+
+    env
+}
+
+// Makes `name` the current namespace, used for top-level forms.
+pub fn switch(env: &Env, name: &str) -> Result<Env, AtomError> {
+    let registry = registry(env)?;
+    let ns_env = find_or_create(&registry, name);
+    registry.borrow_mut().current = name.to_string();
+
+    Ok(ns_env)
+}
+
+pub fn exists(env: &Env, name: &str) -> bool {
+    registry(env).map(|registry| registry.borrow().envs.contains_key(name)).unwrap_or(false)
+}
+
+pub fn current_name(env: &Env) -> Option<String> {
+    registry(env).ok().map(|registry| registry.borrow().current.clone())
+}
+
+pub fn current_env(env: &Env) -> Env {
+    match registry(env) {
+        Ok(registry) => {
+            let registry = registry.borrow();
+            registry.envs.get(&registry.current).cloned().unwrap_or_else(|| env.clone())
+        }
+        Err(_) => env.clone(),
+    }
+}
+
+// Env in which a top-level form should be evaluated: namespace envs follow
+// `ns`/`in-ns` switches, any other env is used as it is.
+pub fn top_level_env(env: &Env) -> Env {
+    if env_is_namespace(env) {
+        current_env(env)
+    } else {
+        env.clone()
+    }
+}
+
+pub fn add_alias(env: &Env, alias: &str, target: &str) -> Result<(), AtomError> {
+    let ns = env_namespace(env)
+        .ok_or_else(|| AtomError::InvalidOperation(format!("alias {} outside of namespace", alias)))?;
+    ns.aliases.borrow_mut().insert(alias.to_string(), target.to_string());
+
+    Ok(())
+}
+
+pub fn mark_private(env: &Env, name: &str) -> Result<(), AtomError> {
+    let ns = env_namespace(env)
+        .ok_or_else(|| AtomError::InvalidOperation(format!("private {} outside of namespace", name)))?;
+    ns.private.borrow_mut().insert(name.to_string());
+
+    Ok(())
+}
+
+// Warning for a new definition in `ns` hiding a core one.
+fn core_shadow_warning(ns: &Namespace, ns_env: &Env, name_atom: &AtomVal) -> Option<String> {
+    if ns.name == CORE_NS || env_get(ns_env, name_atom).is_none() {
+        return None;
+    }
+
+    Some(format!("WARNING: {} already refers to {}/{}, being replaced by {}/{}",
+                 name_atom,
+                 CORE_NS,
+                 name_atom,
+                 ns.name,
+                 name_atom))
+}
+
+// Binds `name` in the namespace env enclosing `env` and returns its var,
+// whose watches are told about redefinitions. Once `dynamic`, the var keeps
+// its value as root and can be rebound by `binding`. Shadowing a core
+// definition from another namespace prints a warning.
+pub fn intern(env: &Env, name_atom: &AtomVal, value: AtomVal, dynamic: bool) -> AtomRet {
+    let name = name_atom.get_symbol()?;
+    let ns_env = env_namespace_env(env);
+    let ns = match env_namespace(&ns_env) {
+        Some(ns) => ns,
+        None => {
+            env_set(&ns_env, name_atom, value)?;
+            return Ok(c_var(name, &ns_env));
+        }
+    };
+
+    let existing = env_get_own(&ns_env, name_atom);
+    if existing.is_none() {
+        if let Some(warning) = core_shadow_warning(&ns, &ns_env, name_atom) {
+            eprintln!("{}", warning);
+        }
+    }
+
+    let var = ns.vars
+        .borrow_mut()
+        .entry(name.to_string())
+        .or_insert_with(|| c_var(&format!("{}/{}", ns.name, name), &ns_env))
+        .clone();
+    let old = match *var {
+        AtomType::Var(ref dynamic_var) if dynamic || dynamic_var.is_dynamic() => {
+            env_set(&ns_env, name_atom, var.clone())?;
+            dynamic_var.set_root(value.clone())
+        }
+        _ => {
+            env_set(&ns_env, name_atom, value.clone())?;
+            existing
+        }
+    };
+    if let Some(old) = old {
+        var.get_watches()?.notify(&var, &old, &value)?;
+    }
+
+    Ok(var)
+}
+
+// Var of `name` in the namespace defining it.
+pub fn find_var(env: &Env, name: &Rc<String>) -> Option<AtomVal> {
+    env_defining_namespace(env, name).and_then(|ns| ns.vars.borrow().get(name.as_str()).cloned())
+}
+
+// Resolves `ns/name`, where ns is an alias or a full namespace name. Private
+// definitions are only visible from their own namespace.
+pub fn resolve(env: &Env, symbol: &str) -> Result<AtomVal, AtomError> {
+    let undefined = || AtomError::UndefinedSymbol(symbol.to_string());
+    let index = match symbol.find('/') {
+        Some(index) if index > 0 && index < symbol.len() - 1 => index,
+        _ => return Err(undefined()),
+    };
+    let (ns_name, name) = (&symbol[..index], &symbol[index + 1..]);
+
+    let ns = env_namespace(env).ok_or_else(&undefined)?;
+    let target_name = ns.aliases.borrow().get(ns_name).cloned().unwrap_or_else(|| ns_name.to_string());
+    let target_env = ns.registry.borrow().envs.get(&target_name).cloned().ok_or_else(&undefined)?;
+
+    if let Some(target) = env_namespace(&target_env) {
+        if target.name != ns.name && target.private.borrow().contains(name) {
+            return Err(AtomError::InvalidOperation(format!("{} is private", symbol)));
+        }
+    }
+
+    env_get(&target_env, &c_symbol(name)).ok_or_else(undefined)
+}
+
+#[allow(unused_must_use)]
+#[cfg(test)]
+mod tests {
+    use core;
+    use data::{AtomError, c_int, c_symbol};
+    use env::env_namespace;
+    use eval::eval_str_all;
+    use super::{current_name, current_env, core_shadow_warning};
+
+    #[test]
+    fn defs_dont_clobber_core() {
+        let env = core::build();
+        eval_str_all("(ns my.lib) (def first (fn* (x) 42))", &env);
+
+        assert_eq!(eval_str_all("(first '(1 2))", &env).unwrap(), c_int(42));
+        eval_str_all("(in-ns 'user)", &env);
+        assert_eq!(eval_str_all("(first '(1 2))", &env).unwrap(), c_int(1));
+        assert_eq!(eval_str_all("(my.lib/first '(1 2))", &env).unwrap(), c_int(42));
+    }
+
+    #[test]
+    fn aliases_and_private() {
+        let env = core::build();
+        eval_str_all("(ns other.lib) (def pub 1) (def- secret 2) (def reveal (fn* () secret))",
+                      &env);
+        eval_str_all("(ns my.lib) (in-ns 'my.lib)", &env);
+        eval_str_all("(ns my.app (:require [other.lib :as o]))", &env);
+
+        assert_eq!(current_name(&env).unwrap(), "my.app");
+        assert_eq!(eval_str_all("o/pub", &env).unwrap(), c_int(1));
+        assert_eq!(eval_str_all("(o/reveal)", &env).unwrap(), c_int(2));
+        assert_eq!(eval_str_all("o/secret", &env).unwrap_err(),
+                   AtomError::InvalidOperation("o/secret is private".to_string()));
+        assert_eq!(eval_str_all("secret", &env).unwrap_err(),
+                   AtomError::UndefinedSymbol("secret".to_string()));
+    }
+
+    #[test]
+    fn private_functions() {
+        let env = core::build();
+        eval_str_all("(ns other.lib) (defn- helper \"Private.\" [x] (+ x 1)) (defn api [x] (helper x))",
+                     &env);
+        eval_str_all("(ns my.app (:require [other.lib :as o]))", &env);
+
+        assert_eq!(eval_str_all("(o/api 1)", &env).unwrap(), c_int(2));
+        assert_eq!(eval_str_all("(o/helper 1)", &env).unwrap_err(),
+                   AtomError::InvalidOperation("o/helper is private".to_string()));
+        assert_eq!(eval_str_all("(other.lib/helper 1)", &env).unwrap_err(),
+                   AtomError::InvalidOperation("other.lib/helper is private".to_string()));
+    }
+
+    #[test]
+    fn redefining_core_warns() {
+        let env = core::build();
+        let user = current_env(&env);
+        let ns = env_namespace(&user).unwrap();
+
+        assert_eq!(core_shadow_warning(&ns, &user, &c_symbol("map")).unwrap(),
+                   "WARNING: map already refers to rulsp.core/map, being replaced by user/map");
+        assert!(core_shadow_warning(&ns, &user, &c_symbol("fresh")).is_none());
+    }
+
+    #[test]
+    fn division_is_not_qualified() {
+        let env = core::build();
+
+        assert_eq!(eval_str_all("(/ 4 2)", &env).unwrap(), c_int(2));
+    }
+}
@@ -1,7 +1,8 @@
 use std::fmt;
 use std::error::Error as StdError;
 use lexer::Token;
-use data::{AtomVal, c_int, c_nil, c_list, c_symbol};
+use data::{AtomVal, AtomMap, c_int, c_nil, c_list, c_vec, c_map, c_set, c_string, c_symbol, c_keyword};
+use map::AtomSet;
 
 #[derive(Debug)]
 pub enum ParseError {
@@ -44,18 +45,52 @@ impl Parser {
         self.parse(0).and_then(|(atom, _end)| Result::Ok(atom))
     }
 
+    pub fn start_all(&self) -> Result<Vec<AtomVal>, ParseError> {
+        let mut atoms = vec![];
+        let mut pos = 0;
+
+        while let Some(start) = self.next_visible(pos) {
+            let (atom, end) = self.parse(start)?;
+            atoms.push(atom);
+            pos = end + 1;
+        }
+
+        Result::Ok(atoms)
+    }
+
     pub fn parse(&self, pos: usize) -> Result<(AtomVal, usize), ParseError> {
         if let Some(token) = self.pop(pos) {
             if token.is_hidden() {
                 self.parse(pos + 1)
             } else {
                 match token {
-                    &Token::Oparen => self.read_list(pos + 1),
+                    &Token::Oparen => {
+                        let (atoms, pos) = self.read_seq(pos + 1, &Token::Cparen)?;
+                        Result::Ok((c_list(&atoms), pos))
+                    }
+                    &Token::Obracket => {
+                        let (atoms, pos) = self.read_seq(pos + 1, &Token::Cbracket)?;
+                        Result::Ok((c_vec(&atoms), pos))
+                    }
+                    &Token::Obrace => {
+                        let (atoms, pos) = self.read_seq(pos + 1, &Token::Cbrace)?;
+                        match AtomMap::from_flat(&atoms) {
+                            Some(map) => Result::Ok((c_map(map), pos)),
+                            None => Result::Err(ParseError::Syntax),
+                        }
+                    }
+                    &Token::Oset => {
+                        let (atoms, pos) = self.read_seq(pos + 1, &Token::Cbrace)?;
+                        Result::Ok((c_set(AtomSet::from_items(&atoms)), pos))
+                    }
                     &Token::Cparen => Result::Ok((c_nil(), pos)),
                     &Token::Int(num) => Result::Ok((c_int(num), pos)),
+                    &Token::Str(ref str) => Result::Ok((c_string(str), pos)),
                     &Token::Identifier(ref str) => {
                         if str.to_uppercase() == "NIL" {
                             Result::Ok((c_nil(), pos))
+                        } else if str.len() > 1 && str.starts_with(':') {
+                            Result::Ok((c_keyword(&str[1..]), pos))
                         } else {
                             Result::Ok((c_symbol(str), pos))
                         }
@@ -76,6 +111,15 @@ impl Parser {
                         let (body, pos) = self.parse(pos + 1)?;
                         Result::Ok((c_list(&[c_symbol("unquote-splicing"), body]), pos))
                     }
+                    &Token::Deref => {
+                        let (body, pos) = self.parse(pos + 1)?;
+                        Result::Ok((c_list(&[c_symbol("deref"), body]), pos))
+                    }
+                    &Token::Meta => {
+                        let (meta, pos) = self.parse(pos + 1)?;
+                        let (body, pos) = self.parse(pos + 1)?;
+                        Result::Ok((c_list(&[c_symbol("with-meta"), body, meta]), pos))
+                    }
                     _ => Result::Err(ParseError::Syntax),
                 }
             }
@@ -88,7 +132,12 @@ impl Parser {
         self.tokens.get(pos)
     }
 
-    fn read_list(&self, pos: usize) -> Result<(AtomVal, usize), ParseError> {
+    fn next_visible(&self, pos: usize) -> Option<usize> {
+        (pos..self.tokens.len()).find(|&pos| !self.tokens[pos].is_hidden())
+    }
+
+    // Reads elements until the `close` token, other closing tokens are errors.
+    fn read_seq(&self, pos: usize, close: &Token) -> Result<(Vec<AtomVal>, usize), ParseError> {
         let mut atoms = vec![];
         let mut pos = pos;
 
@@ -96,9 +145,12 @@ impl Parser {
             if let Some(token) = self.pop(pos) {
                 if !token.is_hidden() {
                     match token {
-                        &Token::Cparen => {
+                        token if token == close => {
                             break;
                         }
+                        token if token.is_close() => {
+                            return Result::Err(ParseError::Syntax);
+                        }
                         _other => {
                             match self.parse(pos) {
                                 Ok((atom, end)) => {
@@ -117,16 +169,70 @@ impl Parser {
             pos += 1;
         }
 
-        Result::Ok((c_list(&atoms), pos))
+        Result::Ok((atoms, pos))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use data::{c_symbol, c_int, c_list};
+    use data::{AtomMap, c_symbol, c_int, c_list, c_vec, c_map, c_set, c_string, c_keyword};
+    use map::AtomSet;
     use lexer::lex;
     use super::Parser;
 
+    #[test]
+    fn test_start_all() {
+        let parser = Parser::new(&lex("(1 2) foo ; comment\n 3 ").unwrap());
+
+        let expected = vec![c_list(&[c_int(1), c_int(2)]), c_symbol("foo"), c_int(3)];
+
+        assert_eq!(parser.start_all().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_string() {
+        let parser = Parser::new(&lex("(\"a (b)\" \"c\\\"\\n\")").unwrap());
+
+        let expected = c_list(&[c_string("a (b)"), c_string("c\"\n")]);
+
+        assert_eq!(parser.start().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_string_escapes() {
+        let parser = Parser::new(&lex("\"a\\tb\\\\\" \"; not a comment\" \"\"").unwrap());
+
+        let expected = vec![c_string("a\tb\\"), c_string("; not a comment"), c_string("")];
+
+        assert_eq!(parser.start_all().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_vec_and_keyword() {
+        let parser = Parser::new(&lex("(ns a [b :as c])").unwrap());
+
+        let expected = c_list(&[c_symbol("ns"),
+                                c_symbol("a"),
+                                c_vec(&[c_symbol("b"), c_keyword("as"), c_symbol("c")])]);
+
+        assert_eq!(parser.start().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_map() {
+        let parser = Parser::new(&lex("{:a 1 \"b\" [2]}").unwrap());
+
+        let expected = AtomMap::from_flat(&[c_keyword("a"), c_int(1), c_string("b"), c_vec(&[c_int(2)])]);
+
+        assert_eq!(parser.start().unwrap(), c_map(expected.unwrap()));
+        assert!(Parser::new(&lex("{:a}").unwrap()).start().is_err());
+    }
+
+    #[test]
+    fn test_mismatched_bracket() {
+        assert!(Parser::new(&lex("(1 2]").unwrap()).start().is_err());
+    }
+
     #[test]
     fn test_apostrophe() {
         let parser = Parser::new(&lex("'(1 2)").unwrap());
@@ -162,4 +268,31 @@ mod tests {
 
         assert_eq!(parser.start().unwrap(), expected);
     }
+
+    #[test]
+    fn test_deref() {
+        let parser = Parser::new(&lex("@a").unwrap());
+
+        let expected = c_list(&[c_symbol("deref"), c_symbol("a")]);
+
+        assert_eq!(parser.start().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_meta() {
+        let parser = Parser::new(&lex("^:dynamic *out*").unwrap());
+
+        let expected = c_list(&[c_symbol("with-meta"), c_symbol("*out*"), c_keyword("dynamic")]);
+
+        assert_eq!(parser.start().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_set() {
+        let parser = Parser::new(&lex("#{1 :a 1}").unwrap());
+
+        let expected = c_set(AtomSet::from_items(&[c_keyword("a"), c_int(1)]));
+
+        assert_eq!(parser.start().unwrap(), expected);
+    }
 }
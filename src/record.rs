@@ -0,0 +1,160 @@
+use std::rc::Rc;
+use data::{AtomVal, AtomType, AtomRet, AtomError, AtomMap, c_int, c_nil, c_record};
+use map::Entry;
+
+// Type defined by `defrecord`: its name and field keywords, in order.
+#[derive(Debug, PartialEq)]
+pub struct RecordType {
+    pub name: String,
+    pub fields: Vec<AtomVal>,
+}
+
+// Instance of a record type. Values of the fields, and of any other keys
+// assoc'ed onto it, are kept in a map.
+#[derive(Clone, Debug)]
+pub struct AtomRecord {
+    kind: Rc<RecordType>,
+    values: AtomMap,
+}
+
+impl AtomRecord {
+    // Instance with every field nil.
+    pub fn empty(kind: RecordType) -> AtomRecord {
+        let mut values = AtomMap::new();
+        for field in &kind.fields {
+            values.insert(field.clone(), c_nil());
+        }
+
+        AtomRecord {
+            kind: Rc::new(kind),
+            values,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.kind.name
+    }
+
+    pub fn is_field(&self, key: &AtomVal) -> bool {
+        self.kind.fields.contains(key)
+    }
+
+    // Both are instances of the same record type.
+    pub fn same_type(&self, other: &AtomRecord) -> bool {
+        Rc::ptr_eq(&self.kind, &other.kind)
+    }
+
+    pub fn get(&self, key: &AtomVal) -> Option<&AtomVal> {
+        self.values.get(key)
+    }
+
+    pub fn values(&self) -> &AtomMap {
+        &self.values
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn assoc(&self, key: AtomVal, value: AtomVal) -> AtomRecord {
+        AtomRecord {
+            kind: self.kind.clone(),
+            values: self.values.assoc(key, value),
+        }
+    }
+
+    // Record without `key`, or a plain map when `key` is one of its fields.
+    pub fn dissoc(&self, key: &AtomVal) -> Result<AtomRecord, AtomMap> {
+        if self.is_field(key) {
+            return Err(self.values.dissoc(key));
+        }
+
+        Ok(AtomRecord {
+            kind: self.kind.clone(),
+            values: self.values.dissoc(key),
+        })
+    }
+
+    // Fields in the order they were declared, then the other keys.
+    pub fn entries(&self) -> Vec<Entry> {
+        let mut entries = self.kind
+            .fields
+            .iter()
+            .filter_map(|field| self.values.get(field).map(|value| (field.clone(), value.clone())))
+            .collect::<Vec<_>>();
+        entries.extend(self.values.iter().filter(|&&(ref key, _)| !self.is_field(key)).cloned());
+        entries
+    }
+}
+
+// Equal when they have the same type and the same values.
+impl PartialEq for AtomRecord {
+    fn eq(&self, other: &AtomRecord) -> bool {
+        self.same_type(other) && self.values == other.values
+    }
+}
+
+// Functions defined by `defrecord` are bound to an empty instance of the
+// record type, given as their first arg.
+
+fn prototype(args: &[AtomVal]) -> &AtomRecord {
+    match *args[0] {
+        AtomType::Record(ref record) => record,
+        _ => unreachable!("record functions are bound to a record"),
+    }
+}
+
+// [proto & values] - ->Name, taking the fields in order
+pub fn positional(args: &[AtomVal]) -> AtomRet {
+    let proto = prototype(args);
+    let values = &args[1..];
+    if values.len() != proto.kind.fields.len() {
+        return Err(AtomError::InvalidArgument(format!("wrong number of args ({}) passed to: ->{}",
+                                                      values.len(),
+                                                      proto.name())));
+    }
+
+    let mut record = proto.clone();
+    for (field, value) in proto.kind.fields.iter().zip(values) {
+        record.values.insert(field.clone(), value.clone());
+    }
+    Ok(c_record(record))
+}
+
+// [proto map] - map->Name, missing fields are nil
+pub fn from_map(args: &[AtomVal]) -> AtomRet {
+    let mut record = prototype(args).clone();
+    match *args.get(1).cloned().unwrap_or_else(c_nil) {
+        AtomType::Map(ref map) => {
+            for &(ref key, ref value) in map.iter() {
+                record.values.insert(key.clone(), value.clone());
+            }
+        }
+        AtomType::Nil => {}
+        ref other => return Err(AtomError::InvalidType("Map".to_string(), other.format(true))),
+    }
+
+    Ok(c_record(record))
+}
+
+// [proto value] - Name?
+pub fn is_instance(args: &[AtomVal]) -> AtomRet {
+    match args.get(1).map(|value| &**value) {
+        Some(&AtomType::Record(ref record)) if record.same_type(prototype(args)) => Ok(c_int(1)),
+        _ => Ok(c_nil()),
+    }
+}
+
+// [proto field record] - Name-field
+pub fn field(args: &[AtomVal]) -> AtomRet {
+    let proto = prototype(args);
+    match args.get(2).map(|value| &**value) {
+        Some(&AtomType::Record(ref record)) if record.same_type(proto) => {
+            Ok(record.get(&args[1]).cloned().unwrap_or_else(c_nil))
+        }
+        _ => {
+            let got = args.get(2).map(|value| value.format(true)).unwrap_or_else(|| "nothing".to_string());
+            Err(AtomError::InvalidType(proto.name().to_string(), got))
+        }
+    }
+}
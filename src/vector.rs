@@ -0,0 +1,315 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+use data::AtomVal;
+
+const BITS: usize = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK: usize = WIDTH - 1;
+
+#[derive(Clone)]
+pub enum Node {
+    Branch(Vec<Rc<Node>>),
+    Leaf(Vec<AtomVal>),
+}
+
+// Node on the way from a new leaf up to `level`.
+fn new_path(level: usize, leaf: Rc<Node>) -> Rc<Node> {
+    if level == 0 {
+        leaf
+    } else {
+        Rc::new(Node::Branch(vec![new_path(level - BITS, leaf)]))
+    }
+}
+
+// Adds the full leaf holding elements up to `index` under `node`.
+fn push_leaf(node: &mut Rc<Node>, level: usize, index: usize, leaf: Rc<Node>) {
+    match *Rc::make_mut(node) {
+        Node::Branch(ref mut children) => {
+            let child = (index >> level) & MASK;
+            if level == BITS {
+                children.push(leaf);
+            } else if child < children.len() {
+                push_leaf(&mut children[child], level - BITS, index, leaf);
+            } else {
+                children.push(new_path(level - BITS, leaf));
+            }
+        }
+        Node::Leaf(_) => unreachable!(),
+    }
+}
+
+fn set_in(node: &mut Rc<Node>, level: usize, index: usize, value: AtomVal) {
+    match *Rc::make_mut(node) {
+        Node::Branch(ref mut children) => {
+            set_in(&mut children[(index >> level) & MASK], level - BITS, index, value)
+        }
+        Node::Leaf(ref mut items) => items[index & MASK] = value,
+    }
+}
+
+// Persistent vector, a trie of 32 element leaves with the last elements kept
+// in a separate tail. Versions share all the nodes they didn't change, so
+// `conj` and `assoc` only copy the tail or a path of the trie. Methods
+// taking `&mut self` change the vector in place wherever its nodes aren't
+// shared, which makes building a vector from scratch as cheap as pushing to
+// a `Vec`.
+#[derive(Clone)]
+pub struct AtomVec {
+    len: usize,
+    // bits the index is shifted by to pick a child of the root
+    shift: usize,
+    root: Rc<Node>,
+    tail: Rc<Vec<AtomVal>>,
+}
+
+impl AtomVec {
+    pub fn new() -> AtomVec {
+        AtomVec {
+            len: 0,
+            shift: BITS,
+            root: Rc::new(Node::Branch(vec![])),
+            tail: Rc::new(vec![]),
+        }
+    }
+
+    pub fn from_items(items: &[AtomVal]) -> AtomVec {
+        let mut vec = AtomVec::new();
+        for item in items {
+            vec.push(item.clone());
+        }
+        vec
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Index of the first element in the tail.
+    fn tail_offset(&self) -> usize {
+        if self.len < WIDTH { 0 } else { ((self.len - 1) >> BITS) << BITS }
+    }
+
+    // Leaf holding the element at `index`, and the index of its first element.
+    fn chunk(&self, index: usize) -> (&[AtomVal], usize) {
+        if index >= self.tail_offset() {
+            return (&self.tail, self.tail_offset());
+        }
+
+        let mut node = &*self.root;
+        let mut level = self.shift;
+        loop {
+            match *node {
+                Node::Branch(ref children) => {
+                    node = &children[(index >> level) & MASK];
+                    level -= BITS;
+                }
+                Node::Leaf(ref items) => return (items, index & !MASK),
+            }
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&AtomVal> {
+        if index >= self.len {
+            return None;
+        }
+
+        let (chunk, start) = self.chunk(index);
+        chunk.get(index - start)
+    }
+
+    pub fn push(&mut self, value: AtomVal) {
+        if self.len - self.tail_offset() < WIDTH {
+            Rc::make_mut(&mut self.tail).push(value);
+            self.len += 1;
+            return;
+        }
+
+        let leaf = Rc::new(Node::Leaf(mem::replace(Rc::make_mut(&mut self.tail), vec![])));
+        if (self.len >> BITS) > (1 << self.shift) {
+            let root = mem::replace(&mut self.root, Rc::new(Node::Branch(vec![])));
+            self.root = Rc::new(Node::Branch(vec![root, new_path(self.shift, leaf)]));
+            self.shift += BITS;
+        } else {
+            push_leaf(&mut self.root, self.shift, self.len - 1, leaf);
+        }
+
+        Rc::make_mut(&mut self.tail).push(value);
+        self.len += 1;
+    }
+
+    // Replaces the element at `index`, false when it is out of bounds.
+    pub fn set(&mut self, index: usize, value: AtomVal) -> bool {
+        if index >= self.len {
+            return false;
+        }
+
+        let tail_offset = self.tail_offset();
+        if index >= tail_offset {
+            Rc::make_mut(&mut self.tail)[index - tail_offset] = value;
+        } else {
+            set_in(&mut self.root, self.shift, index, value);
+        }
+        true
+    }
+
+    // New vector with `value` added at the end, sharing the rest.
+    pub fn conj(&self, value: AtomVal) -> AtomVec {
+        let mut vec = self.clone();
+        vec.push(value);
+        vec
+    }
+
+    // New vector with `value` at `index`, or at the end when `index` is the
+    // length. None when it is out of bounds.
+    pub fn assoc(&self, index: usize, value: AtomVal) -> Option<AtomVec> {
+        if index == self.len {
+            return Some(self.conj(value));
+        }
+
+        let mut vec = self.clone();
+        if vec.set(index, value) { Some(vec) } else { None }
+    }
+
+    // Root of the trie and the tail, for walking the heap.
+    pub fn parts(&self) -> (&Rc<Node>, &Rc<Vec<AtomVal>>) {
+        (&self.root, &self.tail)
+    }
+
+    pub fn iter<'a>(&'a self) -> Iter<'a> {
+        Iter {
+            vec: self,
+            index: 0,
+            chunk: &[],
+        }
+    }
+
+    // Elements as a slice, only copied when they don't all fit in the tail.
+    pub fn as_slice<'a>(&'a self) -> Cow<'a, [AtomVal]> {
+        if self.tail_offset() == 0 {
+            Cow::Borrowed(&self.tail)
+        } else {
+            Cow::Owned(self.iter().cloned().collect())
+        }
+    }
+}
+
+pub struct Iter<'a> {
+    vec: &'a AtomVec,
+    index: usize,
+    // rest of the leaf the next element comes from
+    chunk: &'a [AtomVal],
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a AtomVal;
+
+    fn next(&mut self) -> Option<&'a AtomVal> {
+        if self.index >= self.vec.len {
+            return None;
+        }
+        if self.chunk.is_empty() {
+            let (chunk, start) = self.vec.chunk(self.index);
+            self.chunk = &chunk[self.index - start..];
+        }
+
+        let item = &self.chunk[0];
+        self.chunk = &self.chunk[1..];
+        self.index += 1;
+        Some(item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.vec.len - self.index;
+        (left, Some(left))
+    }
+}
+
+impl<'a> IntoIterator for &'a AtomVec {
+    type Item = &'a AtomVal;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+impl PartialEq for AtomVec {
+    fn eq(&self, other: &AtomVec) -> bool {
+        self.len == other.len && self.iter().zip(other.iter()).all(|(a, b)| a == b)
+    }
+}
+
+impl fmt::Debug for AtomVec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AtomVec;
+    use data::{c_int, AtomVal};
+    use test::Bencher;
+
+    const SIZE: i64 = 100000;
+    const UPDATES: usize = 100;
+
+    #[test]
+    fn push_get_and_set() {
+        let mut vec = AtomVec::new();
+        for n in 0..SIZE {
+            vec.push(c_int(n));
+        }
+        let updated = vec.assoc(40000, c_int(-1)).unwrap().conj(c_int(SIZE));
+
+        assert_eq!(vec.len(), SIZE as usize);
+        assert_eq!(vec.get(1056), Some(&c_int(1056)));
+        assert_eq!(vec.get(40000), Some(&c_int(40000)));
+        assert_eq!(updated.get(40000), Some(&c_int(-1)));
+        assert_eq!(updated.get(SIZE as usize), Some(&c_int(SIZE)));
+        assert_eq!(vec.get(SIZE as usize), None);
+        assert!(vec.iter().cloned().eq((0..SIZE).map(c_int)));
+        assert!(vec.assoc(SIZE as usize + 1, c_int(0)).is_none());
+    }
+
+    fn numbers() -> Vec<AtomVal> {
+        (0..SIZE).map(c_int).collect()
+    }
+
+    #[bench]
+    fn bench_assoc_persistent(b: &mut Bencher) {
+        let vec = AtomVec::from_items(&numbers());
+        b.iter(|| {
+            let mut updated = vec.clone();
+            for n in 0..UPDATES {
+                updated = updated.assoc(n * 997, c_int(-1)).unwrap();
+            }
+            updated
+        });
+    }
+
+    #[bench]
+    fn bench_assoc_copying(b: &mut Bencher) {
+        let vec = numbers();
+        b.iter(|| {
+            let mut updated = vec.clone();
+            for n in 0..UPDATES {
+                updated = updated.clone();
+                updated[n * 997] = c_int(-1);
+            }
+            updated
+        });
+    }
+
+    #[bench]
+    fn bench_build_in_place(b: &mut Bencher) {
+        let items = numbers();
+        b.iter(|| AtomVec::from_items(&items));
+    }
+}